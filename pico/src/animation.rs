@@ -0,0 +1,624 @@
+use embassy_time::Duration;
+
+use crate::math::{self, color_math, fixed};
+
+// Everything is rendered from the time since the effect started, so the same time always gives the same frame
+// (except for the effects that keep their own state like twinkle and fire)
+pub trait Effect {
+    fn render(&mut self, time: Duration, pixels: &mut [u32]);
+
+    // Called when the effect is started again from time 0, effects that remember the last time they ran
+    // have to forget it or they would sit still until the new time catches up
+    fn reset(&mut self) {}
+}
+
+// Seconds into the current cycle of something moving at rate units per second that repeats every cycle units
+// Wrapped in whole us before going to f32, which only has 24 bits and would start skipping frames after a few hours
+fn cycle_seconds(time: Duration, cycle: f32, rate: f32) -> f32 {
+    // A rate of 0 gives an infinite period, which saturates to u64::MAX
+    let period_us = ((cycle * 1_000_000.0) / math::abs32(rate)) as u64;
+    if period_us == 0 {
+        return 0.0;
+    }
+
+    (time.as_micros() % period_us) as f32 / 1_000_000.0
+}
+
+// Small xorshift so effects don't need a hardware random source
+struct Random(u32);
+
+impl Random {
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+
+    fn next_u8(&mut self) -> u8 {
+        (self.next() >> 24) as u8
+    }
+}
+
+pub struct Rainbow {
+    pub speed: f32,      // Degrees of hue per second
+    pub spread: f32,     // Degrees of hue across the whole strip
+    pub saturation: f32,
+    pub luminance: f32,
+}
+
+impl Rainbow {
+    pub fn new(speed: f32, spread: f32) -> Self {
+        Rainbow {
+            speed,
+            spread,
+            saturation: 1.0,
+            luminance: 0.5,
+        }
+    }
+}
+
+impl Effect for Rainbow {
+    fn render(&mut self, time: Duration, pixels: &mut [u32]) {
+        // Only the per frame setup is float, the per LED work is fixed point
+        let start = fixed::to_q16((cycle_seconds(time, 360.0, self.speed) * self.speed) % 360.0);
+        let step = fixed::to_q16(self.spread / pixels.len().max(1) as f32);
+        let saturation = fixed::to_q8(self.saturation);
+        let luminance = fixed::to_q8(self.luminance);
+
+        for (index, pixel) in pixels.iter_mut().enumerate() {
+            let hue = start + (index as i32 * step);
+            *pixel = fixed::hsl_to_rgb(hue, saturation, luminance);
+        }
+    }
+}
+
+pub struct Breathe {
+    pub color: u32,
+    pub period: Duration,
+}
+
+impl Breathe {
+    pub fn new(color: u32, period: Duration) -> Self {
+        Breathe { color, period }
+    }
+}
+
+impl Effect for Breathe {
+    fn render(&mut self, time: Duration, pixels: &mut [u32]) {
+        let half = self.period.as_millis() / 2;
+        let level = if half == 0 {
+            255
+        } else {
+            // Triangle wave, same as the status LED
+            let phase = time.as_millis() % (half * 2);
+            let ramp = if phase < half { phase } else { (half * 2) - phase };
+            ((ramp * 255) / half) as u8
+        };
+
+        pixels.fill(color_math::scale_color(self.color, level));
+    }
+}
+
+// A block of width LEDs running along the strip, fading out behind the head
+pub struct Chase {
+    pub color: u32,
+    pub background: u32,
+    pub width: usize,
+    pub speed: f32, // LEDs per second
+}
+
+impl Chase {
+    pub fn new(color: u32, width: usize, speed: f32) -> Self {
+        Chase {
+            color,
+            background: 0,
+            width,
+            speed,
+        }
+    }
+}
+
+impl Effect for Chase {
+    fn render(&mut self, time: Duration, pixels: &mut [u32]) {
+        let len = pixels.len();
+        if len == 0 {
+            return;
+        }
+
+        let head = (cycle_seconds(time, len as f32, self.speed) * self.speed) as usize % len;
+        let width = self.width.max(1);
+
+        for (index, pixel) in pixels.iter_mut().enumerate() {
+            let behind = (head + len - index) % len;
+            *pixel = if behind < width {
+                let level = 255 - ((behind * 255) / width) as u8;
+                color_math::blend(self.background, self.color, level)
+            } else {
+                self.background
+            };
+        }
+    }
+}
+
+// LEDs light up at random and fade back out
+pub struct Twinkle<const LEDS: usize> {
+    pub color: u32,
+    pub chance: u8,         // Chance out of 255 that a dark LED lights up each frame
+    pub fade_time: Duration, // Time to fade from full to off
+    levels: [u8; LEDS],
+    last_time: Duration,
+    random: Random,
+}
+
+impl<const LEDS: usize> Twinkle<LEDS> {
+    pub fn new(color: u32, chance: u8, fade_time: Duration) -> Self {
+        Twinkle {
+            color,
+            chance,
+            fade_time,
+            levels: [0; LEDS],
+            last_time: Duration::from_ticks(0),
+            random: Random(0x1234_5678),
+        }
+    }
+}
+
+impl<const LEDS: usize> Effect for Twinkle<LEDS> {
+    fn render(&mut self, time: Duration, pixels: &mut [u32]) {
+        let elapsed = time.as_micros().saturating_sub(self.last_time.as_micros());
+        let fade_us = self.fade_time.as_micros().max(1);
+        let fade = ((elapsed * 255) / fade_us).min(255);
+        // Only use up the time that went into whole steps, short frames would round every fade down to 0
+        // otherwise. Once fully faded there is nothing left to carry over
+        self.last_time = if fade == 255 {
+            time
+        } else {
+            self.last_time + Duration::from_micros(((fade * fade_us) + 254) / 255)
+        };
+        let fade = fade as u8;
+
+        for (level, pixel) in self.levels.iter_mut().zip(pixels.iter_mut()) {
+            *level = level.saturating_sub(fade);
+            if *level == 0 && self.random.next_u8() < self.chance {
+                *level = 255;
+            }
+
+            *pixel = color_math::scale_color(self.color, color_math::gamma_correct(*level));
+        }
+    }
+
+    // The lit LEDs carry on fading from where they were
+    fn reset(&mut self) {
+        self.last_time = Duration::from_ticks(0);
+    }
+}
+
+// Classic Fire2012: heat rises from the start of the strip, cools and sparks at random
+pub struct Fire<const LEDS: usize> {
+    pub cooling: u8,  // How much the heat drops each step, higher gives shorter flames
+    pub sparking: u8, // Chance out of 255 of a new spark each step
+    pub step: Duration,
+    heat: [u8; LEDS],
+    last_step: Duration,
+    random: Random,
+}
+
+impl<const LEDS: usize> Fire<LEDS> {
+    pub fn new(cooling: u8, sparking: u8) -> Self {
+        Fire {
+            cooling,
+            sparking,
+            step: Duration::from_millis(16),
+            heat: [0; LEDS],
+            last_step: Duration::from_ticks(0),
+            random: Random(0x8765_4321),
+        }
+    }
+
+    fn advance(&mut self) {
+        let max_cooling = ((self.cooling as usize * 10) / LEDS.max(1)) as u8 + 2;
+        for heat in self.heat.iter_mut() {
+            let cooling = (self.random.next() % max_cooling as u32) as u8;
+            *heat = heat.saturating_sub(cooling);
+        }
+
+        // Heat drifts up and spreads out a little
+        for index in (2..LEDS).rev() {
+            self.heat[index] = ((self.heat[index - 1] as u16 + (self.heat[index - 2] as u16 * 2)) / 3) as u8;
+        }
+
+        if self.random.next_u8() < self.sparking && LEDS > 0 {
+            let index = (self.random.next() % 7.min(LEDS) as u32) as usize;
+            let spark = 160 + (self.random.next_u8() % 96);
+            self.heat[index] = self.heat[index].saturating_add(spark);
+        }
+    }
+
+    // Black -> red -> yellow -> white
+    fn heat_color(heat: u8) -> u32 {
+        let scaled = (heat as u16 * 191) / 255;
+        let ramp = ((scaled & 0x3F) << 2) as u8;
+
+        match scaled {
+            0..=63 => color_math::rgb_to_u32(ramp, 0, 0),
+            64..=127 => color_math::rgb_to_u32(255, ramp, 0),
+            _ => color_math::rgb_to_u32(255, 255, ramp),
+        }
+    }
+}
+
+impl<const LEDS: usize> Effect for Fire<LEDS> {
+    fn render(&mut self, time: Duration, pixels: &mut [u32]) {
+        // Fixed steps so the flames look the same no matter how fast frames are drawn
+        let step = self.step.as_micros().max(1);
+        while time.as_micros().saturating_sub(self.last_step.as_micros()) >= step {
+            self.advance();
+            self.last_step = Duration::from_micros(self.last_step.as_micros() + step);
+        }
+
+        for (heat, pixel) in self.heat.iter().zip(pixels.iter_mut()) {
+            *pixel = Self::heat_color(*heat);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.last_step = Duration::from_ticks(0);
+    }
+}
+
+// Fades from start to end and back across the strip, speed scrolls it (0 keeps it still)
+pub struct Gradient {
+    pub start: u32,
+    pub end: u32,
+    pub speed: f32, // LEDs per second
+}
+
+impl Gradient {
+    pub fn new(start: u32, end: u32) -> Self {
+        Gradient { start, end, speed: 0.0 }
+    }
+}
+
+impl Effect for Gradient {
+    fn render(&mut self, time: Duration, pixels: &mut [u32]) {
+        let len = pixels.len();
+        if len == 0 {
+            return;
+        }
+
+        let offset = (cycle_seconds(time, (len * 2) as f32, self.speed) * self.speed) as usize;
+        for (index, pixel) in pixels.iter_mut().enumerate() {
+            // Mirrored so the scrolling wraps around without a hard edge
+            let position = ((index + offset) % (len * 2)) as i32 - len as i32;
+            let amount = (((len as i32 - position.abs()) * 255) / len as i32) as u8;
+            *pixel = color_math::blend(self.start, self.end, amount);
+        }
+    }
+}
+
+// Bar graph for soil moisture, the fill color goes from dry to wet as the level rises
+pub struct MoistureBar {
+    pub dry: u32,
+    pub wet: u32,
+    pub background: u32,
+    level: u8, // 0 - 255
+}
+
+impl MoistureBar {
+    pub fn new(dry: u32, wet: u32) -> Self {
+        MoistureBar {
+            dry,
+            wet,
+            background: 0,
+            level: 0,
+        }
+    }
+
+    // 0.0 is bone dry, 1.0 is soaked
+    pub fn set_level(&mut self, level: f32) {
+        self.level = (level.clamp(0.0, 1.0) * 255.0) as u8;
+    }
+}
+
+impl Effect for MoistureBar {
+    fn render(&mut self, _time: Duration, pixels: &mut [u32]) {
+        let color = color_math::blend(self.dry, self.wet, self.level);
+        // In 1/255ths of an LED so the last lit LED can be partly on
+        let filled = pixels.len() * self.level as usize;
+
+        for (index, pixel) in pixels.iter_mut().enumerate() {
+            let lit = filled.saturating_sub(index * 255).min(255) as u8;
+            *pixel = color_math::blend(self.background, color, lit);
+        }
+    }
+}
+
+// Runs one effect at a time and crossfades when switching to another one
+pub struct Animator<'a, const LEDS: usize> {
+    current: &'a mut dyn Effect,
+    current_time: Duration,
+    next: Option<&'a mut dyn Effect>,
+    next_time: Duration,
+    fade_time: Duration,
+    scratch: [u32; LEDS],
+}
+
+impl<'a, const LEDS: usize> Animator<'a, LEDS> {
+    pub fn new(effect: &'a mut dyn Effect) -> Self {
+        effect.reset();
+
+        Animator {
+            current: effect,
+            current_time: Duration::from_ticks(0),
+            next: None,
+            next_time: Duration::from_ticks(0),
+            fade_time: Duration::from_ticks(0),
+            scratch: [0; LEDS],
+        }
+    }
+
+    // The new effect starts from its beginning and takes over completely after fade_time
+    pub fn transition_to(&mut self, effect: &'a mut dyn Effect, fade_time: Duration) {
+        // A transition that is still running just gets cut short
+        if let Some(next) = self.next.take() {
+            self.current = next;
+            self.current_time = self.next_time;
+        }
+
+        effect.reset();
+        self.next = Some(effect);
+        self.next_time = Duration::from_ticks(0);
+        self.fade_time = fade_time;
+    }
+
+    pub fn is_transitioning(&self) -> bool {
+        self.next.is_some()
+    }
+
+    // Advances by delta and draws the frame into pixels
+    pub fn render(&mut self, delta: Duration, pixels: &mut [u32; LEDS]) {
+        self.current_time += delta;
+        self.current.render(self.current_time, pixels);
+
+        let Some(next) = self.next.as_mut() else {
+            return;
+        };
+
+        self.next_time += delta;
+        next.render(self.next_time, &mut self.scratch);
+
+        if self.next_time >= self.fade_time {
+            pixels.copy_from_slice(&self.scratch);
+            self.current = self.next.take().unwrap();
+            self.current_time = self.next_time;
+            return;
+        }
+
+        let amount = ((self.next_time.as_micros() * 255) / self.fade_time.as_micros().max(1)) as u8;
+        for (pixel, incoming) in pixels.iter_mut().zip(self.scratch.iter()) {
+            *pixel = color_math::blend(*pixel, *incoming, amount);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEDS: usize = 8;
+    const RED: u32 = 0xFF0000;
+    const BLUE: u32 = 0x0000FF;
+    // Long enough that f32 seconds would only be good to a few hundred ms
+    const UPTIME: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+    struct Solid(u32);
+
+    impl Effect for Solid {
+        fn render(&mut self, _time: Duration, pixels: &mut [u32]) {
+            pixels.fill(self.0);
+        }
+    }
+
+    fn render(effect: &mut dyn Effect, time: Duration) -> [u32; LEDS] {
+        let mut pixels = [0; LEDS];
+        effect.render(time, &mut pixels);
+        pixels
+    }
+
+    #[test]
+    fn rainbow_spreads_hue_and_repeats() {
+        let mut rainbow = Rainbow::new(200.0, 360.0);
+        let frame = render(&mut rainbow, Duration::from_ticks(0));
+
+        for (index, pixel) in frame.iter().enumerate() {
+            let hue = fixed::to_q16((index * 360 / LEDS) as f32);
+            assert_eq!(*pixel, fixed::hsl_to_rgb(hue, fixed::to_q8(1.0), fixed::to_q8(0.5)), "LED {}", index);
+        }
+
+        // 200 degrees per second comes back around every 1.8s
+        let time = Duration::from_millis(250);
+        let expected = render(&mut rainbow, time);
+        assert_eq!(render(&mut rainbow, time + Duration::from_millis(1800)), expected);
+        assert_eq!(render(&mut rainbow, time + (UPTIME * 3)), expected);
+    }
+
+    #[test]
+    fn breathe_ramps_up_and_down() {
+        let mut breathe = Breathe::new(RED, Duration::from_millis(1000));
+        assert_eq!(render(&mut breathe, Duration::from_ticks(0)), [0; LEDS]);
+        assert_eq!(render(&mut breathe, Duration::from_millis(250)), [color_math::scale_color(RED, 127); LEDS]);
+        assert_eq!(render(&mut breathe, Duration::from_millis(500)), [RED; LEDS]);
+        assert_eq!(render(&mut breathe, Duration::from_millis(750)), [color_math::scale_color(RED, 127); LEDS]);
+        assert_eq!(render(&mut breathe, Duration::from_millis(1000)), [0; LEDS]);
+    }
+
+    #[test]
+    fn chase_moves_at_speed() {
+        let mut chase = Chase::new(RED, 2, 10.0);
+        chase.background = BLUE;
+        let tail = color_math::blend(BLUE, RED, 128);
+
+        let frame = render(&mut chase, Duration::from_ticks(0));
+        assert_eq!(frame, [RED, BLUE, BLUE, BLUE, BLUE, BLUE, BLUE, tail]);
+
+        let frame = render(&mut chase, Duration::from_millis(300));
+        assert_eq!(frame, [BLUE, BLUE, tail, RED, BLUE, BLUE, BLUE, BLUE]);
+
+        // Once around the strip takes 0.8s
+        assert_eq!(render(&mut chase, UPTIME + Duration::from_millis(300)), frame);
+    }
+
+    #[test]
+    fn twinkle_fades_over_fade_time() {
+        let mut twinkle = Twinkle::<LEDS>::new(RED, 255, Duration::from_secs(1));
+        assert_eq!(render(&mut twinkle, Duration::from_ticks(0)), [RED; LEDS]);
+
+        // 1ms frames are far less than one step of the fade each
+        twinkle.chance = 0;
+        let mut time = Duration::from_ticks(0);
+        for _ in 0..500 {
+            time += Duration::from_millis(1);
+            render(&mut twinkle, time);
+        }
+        let level = color_math::gamma_correct(128);
+        assert_eq!(twinkle.levels, [128; LEDS]);
+        assert_eq!(render(&mut twinkle, time), [color_math::scale_color(RED, level); LEDS]);
+
+        for _ in 0..500 {
+            time += Duration::from_millis(1);
+            render(&mut twinkle, time);
+        }
+        assert_eq!(render(&mut twinkle, time), [0; LEDS]);
+    }
+
+    #[test]
+    fn twinkle_stays_dark_without_chance() {
+        let mut twinkle = Twinkle::<LEDS>::new(RED, 0, Duration::from_secs(1));
+        for millis in 0..100 {
+            assert_eq!(render(&mut twinkle, Duration::from_millis(millis * 16)), [0; LEDS]);
+        }
+    }
+
+    #[test]
+    fn fire_steps_the_same_at_any_frame_rate() {
+        let mut slow = Fire::<LEDS>::new(55, 120);
+        let mut fast = Fire::<LEDS>::new(55, 120);
+
+        for frame in 1..=60 {
+            render(&mut fast, Duration::from_millis(frame * 5));
+        }
+        assert_eq!(render(&mut slow, Duration::from_millis(300)), render(&mut fast, Duration::from_millis(300)));
+        assert_ne!(render(&mut slow, Duration::from_millis(300)), [0; LEDS]);
+    }
+
+    #[test]
+    fn fire_without_sparks_stays_dark() {
+        let mut fire = Fire::<LEDS>::new(55, 0);
+        assert_eq!(render(&mut fire, Duration::from_secs(2)), [0; LEDS]);
+    }
+
+    #[test]
+    fn gradient_mirrors_and_scrolls() {
+        let mut gradient = Gradient::new(RED, BLUE);
+        let blend = |amount| color_math::blend(RED, BLUE, amount);
+
+        let frame = render(&mut gradient, Duration::from_ticks(0));
+        assert_eq!(frame, [RED, blend(31), blend(63), blend(95), blend(127), blend(159), blend(191), blend(223)]);
+
+        // One LED per 100ms, so after 300ms every LED shows what the one 3 further along had
+        gradient.speed = 10.0;
+        let scrolled = render(&mut gradient, Duration::from_millis(300));
+        assert_eq!(scrolled[..5], frame[3..]);
+        assert_eq!(scrolled[5], BLUE);
+
+        // Back to the start every 1.6s
+        assert_eq!(render(&mut gradient, UPTIME + Duration::from_millis(300)), scrolled);
+    }
+
+    #[test]
+    fn moisture_bar_fills_with_level() {
+        let mut bar = MoistureBar::new(RED, BLUE);
+        assert_eq!(render(&mut bar, Duration::from_ticks(0)), [0; LEDS]);
+
+        bar.set_level(1.0);
+        assert_eq!(render(&mut bar, Duration::from_ticks(0)), [BLUE; LEDS]);
+
+        // 127/255 of 8 LEDs is 3 full ones and most of the fourth
+        bar.set_level(0.5);
+        let color = color_math::blend(RED, BLUE, 127);
+        let frame = render(&mut bar, Duration::from_ticks(0));
+        assert_eq!(frame[..3], [color; 3]);
+        assert_eq!(frame[3], color_math::blend(0, color, 251));
+        assert_eq!(frame[4..], [0; 4]);
+    }
+
+    #[test]
+    fn fire_keeps_burning_after_switching_back() {
+        let mut fire = Fire::<LEDS>::new(55, 200);
+        let mut pixels = [0; LEDS];
+
+        {
+            let mut breathe = Breathe::new(RED, Duration::from_millis(1000));
+            let mut animator = Animator::<LEDS>::new(&mut fire);
+            for _ in 0..200 {
+                animator.render(Duration::from_millis(16), &mut pixels);
+            }
+
+            animator.transition_to(&mut breathe, Duration::from_millis(100));
+            for _ in 0..20 {
+                animator.render(Duration::from_millis(16), &mut pixels);
+            }
+            assert!(!animator.is_transitioning());
+        }
+
+        // Fire was last stepped 3.2s in, it starts over from 0 when it comes back
+        let mut breathe = Breathe::new(RED, Duration::from_millis(1000));
+        let mut animator = Animator::<LEDS>::new(&mut breathe);
+        animator.transition_to(&mut fire, Duration::from_ticks(0));
+
+        let mut last = [0; LEDS];
+        animator.render(Duration::from_millis(16), &mut last);
+        for frame in 0..100 {
+            animator.render(Duration::from_millis(16), &mut pixels);
+            assert_ne!(pixels, last, "frame {}", frame);
+            last = pixels;
+        }
+    }
+
+    #[test]
+    fn twinkle_fades_after_being_restarted() {
+        let mut twinkle = Twinkle::<LEDS>::new(RED, 255, Duration::from_millis(100));
+        render(&mut twinkle, Duration::from_secs(10));
+        twinkle.chance = 0;
+
+        twinkle.reset();
+        assert_eq!(render(&mut twinkle, Duration::from_millis(100)), [0; LEDS]);
+    }
+
+    #[test]
+    fn animator_crossfade_endpoints() {
+        let mut red = Solid(RED);
+        let mut blue = Solid(BLUE);
+        let mut animator = Animator::<LEDS>::new(&mut red);
+        let mut pixels = [0; LEDS];
+
+        animator.render(Duration::from_millis(16), &mut pixels);
+        assert_eq!(pixels, [RED; LEDS]);
+
+        animator.transition_to(&mut blue, Duration::from_millis(100));
+        animator.render(Duration::from_ticks(0), &mut pixels);
+        assert!(animator.is_transitioning());
+        assert_eq!(pixels, [RED; LEDS]);
+
+        animator.render(Duration::from_millis(50), &mut pixels);
+        assert_eq!(pixels, [color_math::blend(RED, BLUE, 127); LEDS]);
+
+        animator.render(Duration::from_millis(50), &mut pixels);
+        assert!(!animator.is_transitioning());
+        assert_eq!(pixels, [BLUE; LEDS]);
+
+        animator.render(Duration::from_millis(16), &mut pixels);
+        assert_eq!(pixels, [BLUE; LEDS]);
+    }
+}
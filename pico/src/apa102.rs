@@ -0,0 +1,79 @@
+use embassy_time::Duration;
+
+use crate::pixel_format::frame_bytes;
+use crate::spi::{SPIDriver, SPIError, SPIFormat, SPIMode, SPIRole, SpiConfig, SpiInstance};
+use crate::ws2812b::{LedDriver, PixelFormat};
+
+const START_FRAME: [u8; 4] = [0; 4];
+const ZEROS: [u8; 16] = [0; 16];
+// Top three bits of every LED frame are always set, the low five are the global brightness
+const LED_FRAME_HEADER: u8 = 0b1110_0000;
+pub const MAX_GLOBAL_BRIGHTNESS: u8 = 31;
+
+// APA102 / SK9822 clocked strips, there is no timing to meet so any clock up to a few MHz works
+// The SPI gets configured here, it only needs DMA channels set. Use the Spi pins for SCK and MOSI
+pub struct Apa102Driver<'a, T: SpiInstance, const LEDS: usize> {
+    spi: &'a SPIDriver<T>,
+    format: PixelFormat,
+    global_brightness: u8,
+    frame: [[u8; 4]; LEDS],
+}
+
+impl<'a, T: SpiInstance, const LEDS: usize> Apa102Driver<'a, T, LEDS> {
+    pub fn config(baudrate: u32) -> SpiConfig {
+        SpiConfig {
+            role: SPIRole::Master,
+            mode: SPIMode::Mode0,
+            data_bits: 8,
+            format: SPIFormat::Motorola,
+            baudrate,
+            timeout: Some(Duration::from_millis(100)),
+        }
+    }
+
+    // Most APA102 strips take the colors as BGR. There's no white LED so RGBW formats fail with LengthMismatch
+    pub fn new(spi: &'a SPIDriver<T>, baudrate: u32, format: PixelFormat) -> Result<Self, SPIError> {
+        if format.channels() != 3 {
+            return Err(SPIError::LengthMismatch);
+        }
+
+        spi.configure(&Self::config(baudrate))?;
+
+        Ok(Apa102Driver {
+            spi,
+            format,
+            global_brightness: MAX_GLOBAL_BRIGHTNESS,
+            frame: [[LED_FRAME_HEADER, 0, 0, 0]; LEDS],
+        })
+    }
+
+    // The strip's own 5 bit current control (0 - 31), on top of LedStrip's brightness
+    pub fn set_global_brightness(&mut self, brightness: u8) {
+        self.global_brightness = brightness.min(MAX_GLOBAL_BRIGHTNESS);
+    }
+}
+
+impl<'a, T: SpiInstance, const LEDS: usize> LedDriver<LEDS> for Apa102Driver<'a, T, LEDS> {
+    type Error = SPIError;
+
+    async fn write(&mut self, pixels: &[u32; LEDS]) -> Result<(), SPIError> {
+        for (frame, color) in self.frame.iter_mut().zip(pixels.iter()) {
+            let channels = self.format.wire_bytes(*color);
+            *frame = [LED_FRAME_HEADER | self.global_brightness, channels[0], channels[1], channels[2]];
+        }
+
+        self.spi.write_bytes_async(&START_FRAME).await?;
+        self.spi.write_bytes_async(frame_bytes(&self.frame)).await?;
+
+        // Every LED delays the data by half a clock so the end needs LEDS / 2 more clocks to push it all through,
+        // the SK9822 also wants 32 zero bits to latch
+        let mut end_bytes = START_FRAME.len() + (LEDS / 16) + 1;
+        while end_bytes > 0 {
+            let chunk = end_bytes.min(ZEROS.len());
+            self.spi.write_bytes_async(&ZEROS[..chunk]).await?;
+            end_bytes -= chunk;
+        }
+
+        Ok(())
+    }
+}
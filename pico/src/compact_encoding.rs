@@ -0,0 +1,266 @@
+use embassy_time::Duration;
+
+use crate::pixel_format::PixelFormat;
+use crate::spi_config::{calculate_baud_divider, SPIError, SPIFormat, SPIMode, SPIRole, SpiConfig};
+use crate::ws2812b_timing::{self, LedChip, SpiLedTiming};
+
+const BIT_FREQUENCY: u32 = 800_000;
+
+// How many SPI bits make up one LED bit, each one starts high and ends low
+//  3 bits: 0 = 100, 1 = 110 (within ~35ns of the WS2812B datasheet at 2.4MHz)
+//  4 bits: 0 = 1000, 1 = 1110 (a third more RAM, T1H and T1L are already 137ns off at exactly 3.2MHz
+//          so it only fits when clk_peri divides down close to that, use timing() to check)
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum BitEncoding {
+    ThreeBit,
+    FourBit,
+}
+
+impl BitEncoding {
+    pub fn spi_bits(&self) -> u32 {
+        match self {
+            BitEncoding::ThreeBit => 3,
+            BitEncoding::FourBit => 4,
+        }
+    }
+
+    // SPI clock that makes each LED bit 1.25us long
+    pub fn baudrate(&self) -> u32 {
+        BIT_FREQUENCY * self.spi_bits()
+    }
+
+    // High SPI bits in a 0 and in a 1
+    fn high_bits(&self) -> (u8, u8) {
+        match self {
+            BitEncoding::ThreeBit => (1, 2),
+            BitEncoding::FourBit => (1, 3),
+        }
+    }
+
+    // Takes the reachable SPI clock closest to baudrate() (either side of it) and checks the times that come
+    // out against the WS2812B datasheet. Fails with BaudRateUnreachable if even the closest is out of tolerance
+    // frame_bits in the result is spi_bits(), the SPI itself still runs 8 bit frames so use spi_config() here
+    pub fn timing(&self, peripheral_frequency: u32) -> Result<SpiLedTiming, SPIError> {
+        let ideal_divider = peripheral_frequency / self.baudrate();
+        let (zero_high, one_high) = self.high_bits();
+        let mut best: Option<SpiLedTiming> = None;
+
+        for divider in [ideal_divider.saturating_sub(1), ideal_divider, ideal_divider + 1] {
+            if divider < 2 {
+                continue;
+            }
+
+            // Rounded up, otherwise the request lands just under the divider and gets the next one down
+            let Ok(baud) = calculate_baud_divider(peripheral_frequency, peripheral_frequency.div_ceil(divider)) else {
+                continue;
+            };
+            let candidate = ws2812b_timing::bit_timing(
+                baud.frequency,
+                self.spi_bits() as u8,
+                zero_high,
+                one_high,
+                &LedChip::Ws2812b.timing()
+            );
+
+            if best.map_or(true, |best| candidate.max_error < best.max_error) {
+                best = Some(candidate);
+            }
+        }
+
+        best.filter(|timing| timing.within_tolerance()).ok_or(SPIError::BaudRateUnreachable)
+    }
+
+    // Plain 8 bit Motorola frames, the encoded bits run across frame boundaries
+    // SPH = 1 since with SPH = 0 the SSP leaves a gap between frames to pulse CSn
+    pub fn spi_config(&self, timing: &SpiLedTiming) -> SpiConfig {
+        SpiConfig {
+            role: SPIRole::Master,
+            mode: SPIMode::Mode1,
+            data_bits: 8,
+            format: SPIFormat::Motorola,
+            baudrate: timing.baudrate,
+            timeout: Some(Duration::from_millis(100)),
+        }
+    }
+
+    // Bytes one pixel turns into
+    pub fn pixel_bytes(&self, format: PixelFormat) -> usize {
+        format.channels() * self.spi_bits() as usize
+    }
+
+    // The 8 bits of a channel expanded to 24 or 32 SPI bits, MSB first
+    fn encode_channel(&self, value: u8) -> u32 {
+        let (zero, one) = match self {
+            BitEncoding::ThreeBit => (0b100, 0b110),
+            BitEncoding::FourBit => (0b1000, 0b1110),
+        };
+
+        (0..8).fold(0, |encoded, bit| {
+            let pattern = if value & (0x80 >> bit) != 0 { one } else { zero };
+            (encoded << self.spi_bits()) | pattern
+        })
+    }
+}
+
+// Turns pixels into the encoded byte stream one byte at a time
+pub struct CompactEncoder<'p> {
+    pixels: core::slice::Iter<'p, u32>,
+    format: PixelFormat,
+    encoding: BitEncoding,
+    channels: [u8; 4],
+    next_channel: usize,
+    bits: u32,
+    pending_bits: u32,
+}
+
+impl<'p> CompactEncoder<'p> {
+    pub fn new(pixels: &'p [u32], format: PixelFormat, encoding: BitEncoding) -> Self {
+        CompactEncoder {
+            pixels: pixels.iter(),
+            format,
+            encoding,
+            channels: [0; 4],
+            next_channel: format.channels(),
+            bits: 0,
+            pending_bits: 0,
+        }
+    }
+}
+
+impl Iterator for CompactEncoder<'_> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        // A channel is always a whole number of bytes so pending_bits only runs out between channels
+        if self.pending_bits == 0 {
+            if self.next_channel == self.format.channels() {
+                self.channels = self.format.wire_bytes(*self.pixels.next()?);
+                self.next_channel = 0;
+            }
+
+            self.bits = self.encoding.encode_channel(self.channels[self.next_channel]);
+            self.pending_bits = 8 * self.encoding.spi_bits();
+            self.next_channel += 1;
+        }
+
+        self.pending_bits -= 8;
+        Some((self.bits >> self.pending_bits) as u8)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let channel_bytes = self.encoding.spi_bits() as usize;
+        let remaining = (self.pending_bits / 8) as usize
+            + ((self.format.channels() - self.next_channel) * channel_bytes)
+            + (self.pixels.len() * self.encoding.pixel_bytes(self.format));
+
+        (remaining, Some(remaining))
+    }
+}
+
+impl ExactSizeIterator for CompactEncoder<'_> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Red 0xFF, green 0x00, blue 0xA5, so Grb sends 0x00, 0xFF, 0xA5
+    const PIXEL: u32 = 0x00_FF00A5;
+
+    fn encode(pixels: &[u32], format: PixelFormat, encoding: BitEncoding) -> Vec<u8> {
+        CompactEncoder::new(pixels, format, encoding).collect()
+    }
+
+    #[test]
+    fn timing_at_common_clocks() {
+        // (clk_peri, encoding, SPI clock or None when nothing is in tolerance)
+        let expected = [
+            (48_000_000, BitEncoding::ThreeBit, Some(2_400_000)),
+            (125_000_000, BitEncoding::ThreeBit, Some(2_403_846)),
+            (133_000_000, BitEncoding::ThreeBit, Some(2_375_000)),
+            // The floor divider gives 3.0MHz and 3.125MHz here, T1H 1000ns and 960ns
+            (48_000_000, BitEncoding::FourBit, None),
+            (125_000_000, BitEncoding::FourBit, Some(3_289_473)),
+            (133_000_000, BitEncoding::FourBit, Some(3_166_666)),
+        ];
+
+        for (peripheral_frequency, encoding, baudrate) in expected {
+            let context = format!("{:?} at {}Hz", encoding, peripheral_frequency);
+            match (encoding.timing(peripheral_frequency), baudrate) {
+                (Ok(timing), Some(baudrate)) => {
+                    assert!(timing.within_tolerance(), "{}", context);
+                    assert_eq!(timing.baudrate, baudrate, "{}", context);
+                    assert_eq!(timing.frame_bits as u32, encoding.spi_bits(), "{}", context);
+                    assert_eq!(encoding.spi_config(&timing).baudrate, baudrate, "{}", context);
+                    assert_eq!(encoding.spi_config(&timing).data_bits, 8, "{}", context);
+                },
+                (Err(error), None) => assert_eq!(error, SPIError::BaudRateUnreachable, "{}", context),
+                (result, _) => panic!("{}: {:?}", context, result),
+            }
+        }
+    }
+
+    #[test]
+    fn three_bit_pixel() {
+        assert_eq!(encode(&[PIXEL], PixelFormat::Grb, BitEncoding::ThreeBit), [
+            0x92, 0x49, 0x24, // 100 100 100 100 100 100 100 100
+            0xDB, 0x6D, 0xB6, // 110 110 110 110 110 110 110 110
+            0xD3, 0x49, 0xA6, // 110 100 110 100 100 110 100 110
+        ]);
+    }
+
+    #[test]
+    fn four_bit_pixel() {
+        assert_eq!(encode(&[PIXEL], PixelFormat::Grb, BitEncoding::FourBit), [
+            0x88, 0x88, 0x88, 0x88,
+            0xEE, 0xEE, 0xEE, 0xEE,
+            0xE8, 0xE8, 0x8E, 0x8E,
+        ]);
+    }
+
+    #[test]
+    fn channels_pack_across_pixels() {
+        let pixels = [PIXEL, 0x80_000001];
+        let encoded = encode(&pixels, PixelFormat::Rgbw { extract_white: false }, BitEncoding::ThreeBit);
+
+        assert_eq!(encoded.len(), 24);
+        // Second pixel's blue 0x01 then white 0x80
+        assert_eq!(encoded[18..], [0x92, 0x49, 0x26, 0xD2, 0x49, 0x24]);
+    }
+
+    #[test]
+    fn length_matches_pixel_bytes() {
+        let pixels = [PIXEL; 5];
+        let formats = [PixelFormat::Grb, PixelFormat::Grbw { extract_white: true }];
+
+        for format in formats {
+            for encoding in [BitEncoding::ThreeBit, BitEncoding::FourBit] {
+                let expected = pixels.len() * encoding.pixel_bytes(format);
+                assert_eq!(CompactEncoder::new(&pixels, format, encoding).len(), expected, "{:?} {:?}", format, encoding);
+                assert_eq!(encode(&pixels, format, encoding).len(), expected, "{:?} {:?}", format, encoding);
+            }
+        }
+
+        assert_eq!(BitEncoding::ThreeBit.pixel_bytes(PixelFormat::Grb), 9);
+        assert_eq!(BitEncoding::FourBit.pixel_bytes(PixelFormat::Rgbw { extract_white: false }), 16);
+        assert_eq!(encode(&[], PixelFormat::Grb, BitEncoding::ThreeBit), []);
+    }
+
+    #[test]
+    fn size_hint_counts_down() {
+        let pixels = [PIXEL; 3];
+
+        for encoding in [BitEncoding::ThreeBit, BitEncoding::FourBit] {
+            let mut encoder = CompactEncoder::new(&pixels, PixelFormat::Grb, encoding);
+            let mut remaining = 3 * encoding.pixel_bytes(PixelFormat::Grb);
+
+            while remaining > 0 {
+                assert_eq!(encoder.size_hint(), (remaining, Some(remaining)), "{:?}", encoding);
+                assert!(encoder.next().is_some());
+                remaining -= 1;
+            }
+
+            assert_eq!(encoder.size_hint(), (0, Some(0)));
+            assert_eq!(encoder.next(), None);
+        }
+    }
+}
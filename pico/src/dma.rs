@@ -0,0 +1,152 @@
+use core::future::poll_fn;
+use core::sync::atomic::{compiler_fence, Ordering};
+use core::task::Poll;
+
+use embassy_rp::interrupt::typelevel::{Binding, Handler, Interrupt, DMA_IRQ_1};
+use embassy_sync::waitqueue::AtomicWaker;
+use rp2040_pac::DMA;
+
+const NUM_CHANNELS: usize = 12;
+
+// Embassy already owns DMA_IRQ_0 so all of our channels complete through DMA_IRQ_1
+#[allow(clippy::declare_interior_mutable_const)]
+const NEW_WAKER: AtomicWaker = AtomicWaker::new();
+static CHANNEL_WAKERS: [AtomicWaker; NUM_CHANNELS] = [NEW_WAKER; NUM_CHANNELS];
+
+// DREQ numbers from the rp2040 datasheet (2.5.3.1)
+pub mod dreq {
+    pub const PIO0_TX0: u8 = 0;
+    pub const PIO0_TX1: u8 = 1;
+    pub const PIO0_TX2: u8 = 2;
+    pub const PIO0_TX3: u8 = 3;
+    pub const PIO1_TX0: u8 = 8;
+    pub const PIO1_TX1: u8 = 9;
+    pub const PIO1_TX2: u8 = 10;
+    pub const PIO1_TX3: u8 = 11;
+    pub const SPI0_TX: u8 = 16;
+    pub const SPI0_RX: u8 = 17;
+    pub const SPI1_TX: u8 = 18;
+    pub const SPI1_RX: u8 = 19;
+}
+
+#[derive(Copy, Clone)]
+pub enum TransferSize {
+    Byte,
+    HalfWord,
+    Word,
+}
+
+#[derive(Copy, Clone)]
+pub struct Transfer {
+    pub read_address: u32,
+    pub write_address: u32,
+    pub count: u32,
+    pub size: TransferSize,
+    pub increment_read: bool,
+    pub increment_write: bool,
+    pub dreq: u8,
+}
+
+// Has to be bound in main with bind_interrupts! (DMA_IRQ_1 => dma::InterruptHandler)
+pub struct InterruptHandler;
+
+impl Handler<DMA_IRQ_1> for InterruptHandler {
+    unsafe fn on_interrupt() {
+        let dma = rp2040_pac::Peripherals::steal().DMA;
+        let finished = dma.ints1().read().ints1().bits();
+
+        dma.ints1().write(|w| w.ints1().bits(finished));
+
+        for (channel, waker) in CHANNEL_WAKERS.iter().enumerate() {
+            if finished & (1 << channel) != 0 {
+                waker.wake();
+            }
+        }
+    }
+}
+
+pub struct DMADriver {
+    dma: DMA,
+}
+
+impl DMADriver {
+    // Irqs from bind_interrupts! has to bind DMA_IRQ_1 to InterruptHandler, the first finished transfer
+    // would go to the default handler otherwise
+    pub fn begin(_irqs: impl Binding<DMA_IRQ_1, InterruptHandler>) -> Self {
+        DMA_IRQ_1::unpend();
+        unsafe { DMA_IRQ_1::enable() };
+
+        DMADriver {
+            dma: unsafe { rp2040_pac::Peripherals::steal().DMA }
+        }
+    }
+
+    // Starts a transfer of count items paced by dreq
+    // Safety: both addresses have to stay valid until the transfer finishes or is aborted
+    pub unsafe fn start(&self, channel: usize, transfer: &Transfer) {
+        let mask = 1u16 << channel;
+
+        // Take the channel away from embassy's interrupt and give it to ours
+        self.dma.inte0().modify(|r, w| w.inte0().bits(r.inte0().bits() & !mask));
+        self.dma.inte1().modify(|r, w| w.inte1().bits(r.inte1().bits() | mask));
+
+        let ch = self.dma.ch(channel);
+        ch.ch_read_addr().write(|w| w.bits(transfer.read_address));
+        ch.ch_write_addr().write(|w| w.bits(transfer.write_address));
+        ch.ch_trans_count().write(|w| w.bits(transfer.count));
+
+        compiler_fence(Ordering::SeqCst);
+
+        ch.ch_ctrl_trig().write(|w| {
+            match transfer.size {
+                TransferSize::Byte => w.data_size().size_byte(),
+                TransferSize::HalfWord => w.data_size().size_halfword(),
+                TransferSize::Word => w.data_size().size_word(),
+            };
+            w.incr_read().bit(transfer.increment_read);
+            w.incr_write().bit(transfer.increment_write);
+            // Chaining to itself means no chaining
+            w.chain_to().bits(channel as u8);
+            w.treq_sel().bits(transfer.dreq);
+            w.en().set_bit()
+        });
+    }
+
+    pub fn is_busy(&self, channel: usize) -> bool {
+        self.dma.ch(channel).ch_ctrl_trig().read().busy().bit_is_set()
+    }
+
+    pub fn abort(&self, channel: usize) {
+        self.dma.chan_abort().write(|w| unsafe { w.chan_abort().bits(1 << channel) });
+        while self.dma.chan_abort().read().chan_abort().bits() & (1 << channel) != 0 {}
+    }
+
+    // Waits for the channel to finish, if this future is dropped early the transfer gets aborted
+    pub async fn wait(&self, channel: usize) {
+        let guard = AbortOnDrop { driver: self, channel };
+
+        poll_fn(|cx| {
+            CHANNEL_WAKERS[channel].register(cx.waker());
+
+            if self.is_busy(channel) {
+                Poll::Pending
+            } else {
+                Poll::Ready(())
+            }
+        }).await;
+
+        compiler_fence(Ordering::SeqCst);
+        core::mem::forget(guard);
+    }
+}
+
+struct AbortOnDrop<'a> {
+    driver: &'a DMADriver,
+    channel: usize,
+}
+
+impl Drop for AbortOnDrop<'_> {
+    fn drop(&mut self) {
+        self.driver.abort(self.channel);
+    }
+}
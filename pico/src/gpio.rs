@@ -16,6 +16,9 @@ pub enum CtrlStatus {
     High,
     Pwm,
     Spi,
+    SpiSelected, // Spi function with the input held low, for a slave CSn that is always selected
+    Pio0,
+    Pio1,
 }
 
 pub struct GPIODriver {
@@ -62,6 +65,22 @@ impl GPIODriver {
                     w.funcsel().spi()
                 });
             },
+            CtrlStatus::SpiSelected => {
+                self.io_bank0.gpio(pin).gpio_ctrl().write(|w| {
+                    w.funcsel().spi();
+                    w.inover().low()
+                });
+            },
+            CtrlStatus::Pio0 => {
+                self.io_bank0.gpio(pin).gpio_ctrl().write(|w| {
+                    w.funcsel().pio0()
+                });
+            },
+            CtrlStatus::Pio1 => {
+                self.io_bank0.gpio(pin).gpio_ctrl().write(|w| {
+                    w.funcsel().pio1()
+                });
+            },
         }
     }
 
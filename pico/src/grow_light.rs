@@ -0,0 +1,231 @@
+use core::ops::Range;
+
+use embassy_time::Instant;
+
+use crate::gpio::{CtrlStatus, GPIODriver};
+use crate::math::color_math;
+use crate::pwm::PWMDriver;
+
+const SECONDS_PER_DAY: u32 = 24 * 60 * 60;
+
+// Relative level of each channel from 0.0 to 1.0, also used for the levels that get written out
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Spectrum {
+    pub red: f32,
+    pub blue: f32,
+    pub white: f32,
+    pub far_red: f32,
+}
+
+impl Spectrum {
+    pub const OFF: Spectrum = Spectrum { red: 0.0, blue: 0.0, white: 0.0, far_red: 0.0 };
+
+    fn scaled(&self, scale: f32) -> Spectrum {
+        Spectrum {
+            red: (self.red * scale).clamp(0.0, 1.0),
+            blue: (self.blue * scale).clamp(0.0, 1.0),
+            white: (self.white * scale).clamp(0.0, 1.0),
+            far_red: (self.far_red * scale).clamp(0.0, 1.0),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SpectrumPreset {
+    Seedling,   // Mostly blue and gentle, keeps seedlings short and sturdy
+    Vegetative, // Blue heavy for leafy growth
+    Flowering,  // Red and far red push flowering and fruiting
+}
+
+impl SpectrumPreset {
+    pub fn spectrum(&self) -> Spectrum {
+        match self {
+            SpectrumPreset::Seedling => Spectrum { red: 0.3, blue: 0.7, white: 0.5, far_red: 0.0 },
+            SpectrumPreset::Vegetative => Spectrum { red: 0.6, blue: 1.0, white: 0.8, far_red: 0.1 },
+            SpectrumPreset::Flowering => Spectrum { red: 1.0, blue: 0.4, white: 0.7, far_red: 0.6 },
+        }
+    }
+
+    // Hours of light a day that usually go with the preset
+    pub fn photoperiod_hours(&self) -> u32 {
+        match self {
+            SpectrumPreset::Seedling => 16,
+            SpectrumPreset::Vegetative => 18,
+            SpectrumPreset::Flowering => 12,
+        }
+    }
+}
+
+// When the lights are on each day, the ramps are part of the on time
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Photoperiod {
+    pub start: u32,  // Seconds after midnight the sunrise starts
+    pub length: u32, // Seconds from the start of sunrise to the end of sunset
+    pub ramp: u32,   // Seconds each of sunrise and sunset take
+}
+
+impl Photoperiod {
+    pub fn hours(start_hour: u32, hours: u32, ramp_minutes: u32) -> Self {
+        Photoperiod {
+            start: (start_hour * 60 * 60) % SECONDS_PER_DAY,
+            length: (hours * 60 * 60).min(SECONDS_PER_DAY),
+            ramp: ramp_minutes * 60,
+        }
+    }
+
+    // How much daylight there is right now from 0.0 to 1.0
+    pub fn daylight(&self, time_of_day: u32) -> f32 {
+        if self.length >= SECONDS_PER_DAY {
+            return 1.0;
+        }
+
+        let since_start = (time_of_day + SECONDS_PER_DAY - self.start) % SECONDS_PER_DAY;
+        if since_start >= self.length {
+            return 0.0;
+        }
+
+        // Ramps can't take more than half of the on time each
+        let ramp = self.ramp.min(self.length / 2);
+        if ramp == 0 {
+            return 1.0;
+        }
+
+        let until_end = self.length - since_start;
+        (since_start.min(until_end).min(ramp) as f32) / ramp as f32
+    }
+}
+
+// There is no RTC running so the time of day is set once (e.g. from the host) and counted from there
+pub struct DayClock {
+    set_at: Instant,
+    seconds_at_set: u32,
+}
+
+impl DayClock {
+    pub fn new(time_of_day: u32) -> Self {
+        DayClock {
+            set_at: Instant::now(),
+            seconds_at_set: time_of_day % SECONDS_PER_DAY,
+        }
+    }
+
+    pub fn set(&mut self, time_of_day: u32) {
+        *self = DayClock::new(time_of_day);
+    }
+
+    // Seconds since midnight
+    pub fn now(&self) -> u32 {
+        ((self.seconds_at_set as u64 + self.set_at.elapsed().as_secs()) % SECONDS_PER_DAY as u64) as u32
+    }
+}
+
+pub struct GrowLight {
+    spectrum: Spectrum,
+    intensity: f32,
+    photoperiod: Photoperiod,
+}
+
+impl GrowLight {
+    pub fn new(preset: SpectrumPreset, photoperiod: Photoperiod) -> Self {
+        GrowLight {
+            spectrum: preset.spectrum(),
+            intensity: 1.0,
+            photoperiod,
+        }
+    }
+
+    pub fn set_preset(&mut self, preset: SpectrumPreset) {
+        self.spectrum = preset.spectrum();
+    }
+
+    pub fn set_spectrum(&mut self, spectrum: Spectrum) {
+        self.spectrum = spectrum;
+    }
+
+    // Overall light output at full daylight from 0.0 to 1.0, the spectrum keeps its balance
+    pub fn set_intensity(&mut self, intensity: f32) {
+        self.intensity = intensity.clamp(0.0, 1.0);
+    }
+
+    pub fn set_photoperiod(&mut self, photoperiod: Photoperiod) {
+        self.photoperiod = photoperiod;
+    }
+
+    pub fn photoperiod(&self) -> &Photoperiod {
+        &self.photoperiod
+    }
+
+    // Channel levels for a time of day in seconds since midnight
+    pub fn levels(&self, time_of_day: u32) -> Spectrum {
+        self.spectrum.scaled(self.intensity * self.photoperiod.daylight(time_of_day))
+    }
+}
+
+// One PWM pin per channel, channels the fixture doesn't have are None
+pub struct PwmGrowChannels<'a> {
+    pwm_driver: &'a PWMDriver,
+    pins: [Option<usize>; 4], // red, blue, white, far red
+}
+
+impl<'a> PwmGrowChannels<'a> {
+    pub fn new(
+        pwm_driver: &'a PWMDriver,
+        gpio_driver: &GPIODriver,
+        red: Option<usize>,
+        blue: Option<usize>,
+        white: Option<usize>,
+        far_red: Option<usize>
+    ) -> Self {
+        let pins = [red, blue, white, far_red];
+
+        for pin in pins.iter().flatten() {
+            gpio_driver.set_pin(*pin, CtrlStatus::Pwm);
+            pwm_driver.start_pwm(*pin);
+            pwm_driver.set_pwm_value_percent(*pin, 0.0);
+        }
+
+        PwmGrowChannels { pwm_driver, pins }
+    }
+
+    pub fn write(&self, levels: &Spectrum) {
+        let values = [levels.red, levels.blue, levels.white, levels.far_red];
+
+        for (pin, value) in self.pins.iter().zip(values) {
+            if let Some(pin) = pin {
+                self.pwm_driver.set_pwm_value_percent(*pin, value);
+            }
+        }
+    }
+}
+
+// Parts of an LED strip given over to each channel, far red uses the red LEDs of its segment
+// (or a strip of far red LEDs wired as the red channel)
+pub struct StripGrowSegments {
+    pub red: Range<usize>,
+    pub blue: Range<usize>,
+    pub white: Range<usize>,
+    pub far_red: Range<usize>,
+    pub rgbw: bool, // White goes to the white byte instead of all three colors
+}
+
+impl StripGrowSegments {
+    // Draws into the strip's color buffer, LEDs outside the segments are left alone
+    pub fn render(&self, levels: &Spectrum, pixels: &mut [u32]) {
+        let level = |value: f32| (value * 255.0) as u8;
+        let white = level(levels.white);
+
+        let segments = [
+            (&self.red, color_math::rgb_to_u32(level(levels.red), 0, 0)),
+            (&self.blue, color_math::rgb_to_u32(0, 0, level(levels.blue))),
+            (&self.far_red, color_math::rgb_to_u32(level(levels.far_red), 0, 0)),
+            (&self.white, if self.rgbw { (white as u32) << 24 } else { color_math::rgb_to_u32(white, white, white) }),
+        ];
+
+        for (range, color) in segments {
+            let end = range.end.min(pixels.len());
+            if range.start < end {
+                pixels[range.start..end].fill(color);
+            }
+        }
+    }
+}
@@ -0,0 +1,367 @@
+// Maps x, y on a grid of LEDs to the index of that LED on the strip, then draws into a color buffer
+// (the same [u32] buffer LedStrip::pixels_mut() and generate_addressable_led_buffer use)
+
+// How the strip is wired through the grid before any rotation
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum MatrixLayout {
+    RowMajor,              // Every row starts on the left
+    ColumnMajor,           // Every column starts at the top
+    SerpentineRows,        // Rows alternate left to right and right to left
+    SerpentineColumns,     // Columns alternate top to bottom and bottom to top
+}
+
+// Clockwise, turns the picture so the matrix can be mounted any way up
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Rotation {
+    None,
+    Quarter,
+    Half,
+    ThreeQuarter,
+}
+
+pub struct LedMatrix<'a> {
+    pixels: &'a mut [u32],
+    width: usize,  // Size of the panel as it's wired
+    height: usize,
+    layout: MatrixLayout,
+    rotation: Rotation,
+}
+
+impl<'a> LedMatrix<'a> {
+    // pixels has to hold at least width * height LEDs
+    pub fn new(pixels: &'a mut [u32], width: usize, height: usize, layout: MatrixLayout, rotation: Rotation) -> Self {
+        LedMatrix {
+            pixels,
+            width,
+            height,
+            layout,
+            rotation,
+        }
+    }
+
+    // Size after rotation, what the drawing functions see
+    pub fn width(&self) -> usize {
+        match self.rotation {
+            Rotation::None | Rotation::Half => self.width,
+            Rotation::Quarter | Rotation::ThreeQuarter => self.height,
+        }
+    }
+
+    pub fn height(&self) -> usize {
+        match self.rotation {
+            Rotation::None | Rotation::Half => self.height,
+            Rotation::Quarter | Rotation::ThreeQuarter => self.width,
+        }
+    }
+
+    // Strip index of x, y or None if it's off the matrix
+    pub fn index(&self, x: i32, y: i32) -> Option<usize> {
+        if x < 0 || y < 0 || x as usize >= self.width() || y as usize >= self.height() {
+            return None;
+        }
+
+        let (x, y) = (x as usize, y as usize);
+        let (x, y) = match self.rotation {
+            Rotation::None => (x, y),
+            Rotation::Quarter => (y, self.height - 1 - x),
+            Rotation::Half => (self.width - 1 - x, self.height - 1 - y),
+            Rotation::ThreeQuarter => (self.width - 1 - y, x),
+        };
+
+        let index = match self.layout {
+            MatrixLayout::RowMajor => (y * self.width) + x,
+            MatrixLayout::ColumnMajor => (x * self.height) + y,
+            MatrixLayout::SerpentineRows => {
+                if y % 2 == 0 {
+                    (y * self.width) + x
+                } else {
+                    (y * self.width) + (self.width - 1 - x)
+                }
+            },
+            MatrixLayout::SerpentineColumns => {
+                if x % 2 == 0 {
+                    (x * self.height) + y
+                } else {
+                    (x * self.height) + (self.height - 1 - y)
+                }
+            },
+        };
+
+        (index < self.pixels.len()).then_some(index)
+    }
+
+    pub fn set_pixel(&mut self, x: i32, y: i32, color: u32) {
+        if let Some(index) = self.index(x, y) {
+            self.pixels[index] = color;
+        }
+    }
+
+    pub fn pixel(&self, x: i32, y: i32) -> Option<u32> {
+        self.index(x, y).map(|index| self.pixels[index])
+    }
+
+    pub fn fill(&mut self, color: u32) {
+        let count = (self.width * self.height).min(self.pixels.len());
+        self.pixels[..count].fill(color);
+    }
+
+    pub fn clear(&mut self) {
+        self.fill(0);
+    }
+
+    // Bresenham, anything off the matrix is clipped
+    pub fn line(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, color: u32) {
+        let dx = (x1 - x0).abs();
+        let dy = -(y1 - y0).abs();
+        let step_x = if x0 < x1 { 1 } else { -1 };
+        let step_y = if y0 < y1 { 1 } else { -1 };
+        let mut error = dx + dy;
+        let (mut x, mut y) = (x0, y0);
+
+        loop {
+            self.set_pixel(x, y, color);
+            if x == x1 && y == y1 {
+                break;
+            }
+
+            let doubled = error * 2;
+            if doubled >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if doubled <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+    }
+
+    pub fn rect(&mut self, x: i32, y: i32, width: i32, height: i32, color: u32) {
+        if width <= 0 || height <= 0 {
+            return;
+        }
+
+        let (right, bottom) = (x + width - 1, y + height - 1);
+        self.line(x, y, right, y, color);
+        self.line(x, bottom, right, bottom, color);
+        self.line(x, y, x, bottom, color);
+        self.line(right, y, right, bottom, color);
+    }
+
+    pub fn fill_rect(&mut self, x: i32, y: i32, width: i32, height: i32, color: u32) {
+        for row in y..y + height {
+            for column in x..x + width {
+                self.set_pixel(column, row, color);
+            }
+        }
+    }
+
+    // Draws one character of the tiny font with its top left corner at x, y
+    // Returns false for characters the font doesn't have
+    pub fn draw_char(&mut self, x: i32, y: i32, character: char, color: u32) -> bool {
+        let Some(glyph) = glyph(character) else {
+            return false;
+        };
+
+        for (row, bits) in glyph.iter().enumerate() {
+            for column in 0..FONT_WIDTH {
+                if bits & (0b100 >> column) != 0 {
+                    self.set_pixel(x + column as i32, y + row as i32, color);
+                }
+            }
+        }
+
+        true
+    }
+
+    // Draws the characters left to right with a one LED gap, returns the x after the last one
+    pub fn draw_text(&mut self, x: i32, y: i32, text: &str, color: u32) -> i32 {
+        let mut x = x;
+        for character in text.chars() {
+            if self.draw_char(x, y, character, color) {
+                x += FONT_WIDTH as i32 + 1;
+            }
+        }
+        x
+    }
+
+    // Right aligned so a changing reading doesn't jump around, up to 10 digits
+    pub fn draw_number(&mut self, right: i32, y: i32, value: u32, color: u32) {
+        let mut digits = [0u8; 10];
+        let mut count = 0;
+        let mut value = value;
+
+        loop {
+            digits[count] = b'0' + (value % 10) as u8;
+            count += 1;
+            value /= 10;
+            if value == 0 {
+                break;
+            }
+        }
+
+        let mut x = right - FONT_WIDTH as i32 + 1;
+        for digit in digits[..count].iter() {
+            self.draw_char(x, y, *digit as char, color);
+            x -= FONT_WIDTH as i32 + 1;
+        }
+    }
+}
+
+pub const FONT_WIDTH: usize = 3;
+pub const FONT_HEIGHT: usize = 5;
+
+// 3x5 glyphs, one row per entry with the left column in bit 2
+fn glyph(character: char) -> Option<[u8; FONT_HEIGHT]> {
+    let glyph = match character {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b010, 0b010, 0b010],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        '%' => [0b101, 0b001, 0b010, 0b100, 0b101],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        ' ' => [0b000; FONT_HEIGHT],
+        _ => return None,
+    };
+
+    Some(glyph)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 4 wide, 3 tall as wired
+    const WIDTH: usize = 4;
+    const HEIGHT: usize = 3;
+
+    fn index(layout: MatrixLayout, rotation: Rotation, x: i32, y: i32) -> Option<usize> {
+        let mut pixels = [0; WIDTH * HEIGHT];
+        LedMatrix::new(&mut pixels, WIDTH, HEIGHT, layout, rotation).index(x, y)
+    }
+
+    #[test]
+    fn layout_corners() {
+        // (layout, top left, top right, bottom left, bottom right, start of the second row)
+        let expected = [
+            (MatrixLayout::RowMajor, 0, 3, 8, 11, 4),
+            (MatrixLayout::ColumnMajor, 0, 9, 2, 11, 1),
+            (MatrixLayout::SerpentineRows, 0, 3, 8, 11, 7),
+            (MatrixLayout::SerpentineColumns, 0, 11, 2, 9, 1),
+        ];
+
+        for (layout, top_left, top_right, bottom_left, bottom_right, second_row) in expected {
+            assert_eq!(index(layout, Rotation::None, 0, 0), Some(top_left), "{:?}", layout);
+            assert_eq!(index(layout, Rotation::None, 3, 0), Some(top_right), "{:?}", layout);
+            assert_eq!(index(layout, Rotation::None, 0, 2), Some(bottom_left), "{:?}", layout);
+            assert_eq!(index(layout, Rotation::None, 3, 2), Some(bottom_right), "{:?}", layout);
+            assert_eq!(index(layout, Rotation::None, 0, 1), Some(second_row), "{:?}", layout);
+        }
+
+        // Odd columns run bottom to top
+        assert_eq!(index(MatrixLayout::SerpentineColumns, Rotation::None, 1, 0), Some(5));
+    }
+
+    #[test]
+    fn rotation_corners() {
+        // (rotation, width, height, top left, top right, bottom left, bottom right) on a row major panel
+        let expected = [
+            (Rotation::None, 4, 3, 0, 3, 8, 11),
+            (Rotation::Quarter, 3, 4, 8, 0, 11, 3),
+            (Rotation::Half, 4, 3, 11, 8, 3, 0),
+            (Rotation::ThreeQuarter, 3, 4, 3, 11, 0, 8),
+        ];
+
+        for (rotation, width, height, top_left, top_right, bottom_left, bottom_right) in expected {
+            let mut pixels = [0; WIDTH * HEIGHT];
+            let matrix = LedMatrix::new(&mut pixels, WIDTH, HEIGHT, MatrixLayout::RowMajor, rotation);
+            let (right, bottom) = (width as i32 - 1, height as i32 - 1);
+
+            assert_eq!((matrix.width(), matrix.height()), (width, height), "{:?}", rotation);
+            assert_eq!(matrix.index(0, 0), Some(top_left), "{:?}", rotation);
+            assert_eq!(matrix.index(right, 0), Some(top_right), "{:?}", rotation);
+            assert_eq!(matrix.index(0, bottom), Some(bottom_left), "{:?}", rotation);
+            assert_eq!(matrix.index(right, bottom), Some(bottom_right), "{:?}", rotation);
+        }
+    }
+
+    #[test]
+    fn every_led_is_used_once() {
+        let layouts = [MatrixLayout::RowMajor, MatrixLayout::ColumnMajor, MatrixLayout::SerpentineRows, MatrixLayout::SerpentineColumns];
+        let rotations = [Rotation::None, Rotation::Quarter, Rotation::Half, Rotation::ThreeQuarter];
+
+        for layout in layouts {
+            for rotation in rotations {
+                let mut pixels = [0; WIDTH * HEIGHT];
+                let matrix = LedMatrix::new(&mut pixels, WIDTH, HEIGHT, layout, rotation);
+                let mut seen = [false; WIDTH * HEIGHT];
+
+                for y in 0..matrix.height() as i32 {
+                    for x in 0..matrix.width() as i32 {
+                        let index = matrix.index(x, y).unwrap();
+                        assert!(!seen[index], "{:?} {:?} at {}, {}", layout, rotation, x, y);
+                        seen[index] = true;
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn off_the_matrix_is_none() {
+        for rotation in [Rotation::None, Rotation::Quarter, Rotation::Half, Rotation::ThreeQuarter] {
+            let mut pixels = [0; WIDTH * HEIGHT];
+            let mut matrix = LedMatrix::new(&mut pixels, WIDTH, HEIGHT, MatrixLayout::SerpentineRows, rotation);
+            let (width, height) = (matrix.width() as i32, matrix.height() as i32);
+
+            for (x, y) in [(-1, 0), (0, -1), (width, 0), (0, height), (width, height), (i32::MIN, i32::MAX)] {
+                assert_eq!(matrix.index(x, y), None, "{:?} at {}, {}", rotation, x, y);
+                assert_eq!(matrix.pixel(x, y), None, "{:?} at {}, {}", rotation, x, y);
+                // Clipped, not a panic
+                matrix.set_pixel(x, y, 0xFFFFFF);
+            }
+        }
+    }
+
+    #[test]
+    fn short_buffer_is_clipped() {
+        let mut pixels = [0; 10];
+        let mut matrix = LedMatrix::new(&mut pixels, WIDTH, HEIGHT, MatrixLayout::RowMajor, Rotation::None);
+
+        assert_eq!(matrix.index(1, 2), Some(9));
+        assert_eq!(matrix.index(2, 2), None);
+        matrix.fill(0x123456);
+        assert_eq!(pixels, [0x123456; 10]);
+    }
+
+    #[test]
+    fn draw_number_is_right_aligned() {
+        const COLOR: u32 = 0x00FF00;
+        let mut pixels = [0; 12 * FONT_HEIGHT];
+        let mut matrix = LedMatrix::new(&mut pixels, 12, FONT_HEIGHT, MatrixLayout::RowMajor, Rotation::None);
+        matrix.draw_number(11, 0, 42, COLOR);
+
+        // '2' in columns 9 - 11, a gap, then '4' in 5 - 7
+        let row = |matrix: &LedMatrix, y: i32| -> [bool; 12] {
+            core::array::from_fn(|x| matrix.pixel(x as i32, y) == Some(COLOR))
+        };
+        let (o, x) = (false, true);
+        assert_eq!(row(&matrix, 0), [o, o, o, o, o, x, o, x, o, x, x, x]);
+        assert_eq!(row(&matrix, 2), [o, o, o, o, o, x, x, x, o, x, x, x]);
+        assert_eq!(row(&matrix, 4), [o, o, o, o, o, o, o, x, o, x, x, x]);
+
+        matrix.clear();
+        matrix.draw_number(11, 0, 0, COLOR);
+        assert_eq!(row(&matrix, 1), [o, o, o, o, o, o, o, o, o, x, o, x]);
+
+        // All 10 digits of the largest value, the ones that run off the left are clipped
+        matrix.draw_number(11, 0, u32::MAX, COLOR);
+    }
+}
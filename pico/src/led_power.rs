@@ -0,0 +1,132 @@
+use crate::math::color_math;
+
+// Current each channel draws at full brightness plus what every LED draws when it's off, all in mA
+#[derive(Copy, Clone, Debug)]
+pub struct PowerModel {
+    pub red: u32,
+    pub green: u32,
+    pub blue: u32,
+    pub white: u32, // Only RGBW parts, the white byte is 0 otherwise
+    pub idle: u32,
+}
+
+impl PowerModel {
+    // Worst case numbers, real parts are usually a bit under
+    pub const WS2812B: PowerModel = PowerModel { red: 20, green: 20, blue: 20, white: 0, idle: 1 };
+    pub const SK6812_RGBW: PowerModel = PowerModel { red: 20, green: 20, blue: 20, white: 20, idle: 1 };
+    pub const APA102: PowerModel = PowerModel { red: 20, green: 20, blue: 20, white: 0, idle: 1 };
+}
+
+// Estimated draw of a frame of 0xWWRRGGBB pixels in mA
+pub fn estimate_current(pixels: &[u32], model: &PowerModel) -> u32 {
+    let mut total_ua: u32 = 0;
+
+    for pixel in pixels {
+        let (red, green, blue) = color_math::u32_to_rgb(*pixel);
+        let white = (*pixel >> 24) as u8;
+
+        // mA * 1000 / 255 per step keeps it in whole uA
+        total_ua += ((red as u32 * model.red)
+            + (green as u32 * model.green)
+            + (blue as u32 * model.blue)
+            + (white as u32 * model.white)) * 1000 / 255;
+    }
+
+    (total_ua / 1000) + (model.idle * pixels.len() as u32)
+}
+
+// Scale (255 = unchanged) that brings a frame drawing current mA down to budget mA,
+// the idle current doesn't change with brightness so it comes off the budget first
+pub fn budget_scale(current: u32, idle: u32, budget: u32) -> u8 {
+    if current <= budget {
+        return 255;
+    }
+
+    let color_current = current.saturating_sub(idle);
+    let available = budget.saturating_sub(idle);
+    if color_current == 0 {
+        return 255;
+    }
+
+    // One less since scale8 multiplies by scale + 1 and divides by 256, which can round a channel up past
+    // value * scale / 255. With one less it never goes over and over a whole frame that adds up
+    ((available * 255) / color_current).min(255).saturating_sub(1) as u8
+}
+
+// Same as color_math::scale_color but keeps and scales the white byte too
+pub fn scale_pixel(pixel: u32, scale: u8) -> u32 {
+    let white = color_math::scale8((pixel >> 24) as u8, scale);
+    ((white as u32) << 24) | color_math::scale_color(pixel & 0xFFFFFF, scale)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Same steps as LedStrip::show, returns what the scaled frame draws
+    fn scaled_current(frame: &[u32], model: &PowerModel, budget: u32) -> u32 {
+        let requested = estimate_current(frame, model);
+        let scale = budget_scale(requested, model.idle * frame.len() as u32, budget);
+        let scaled: Vec<u32> = frame.iter().map(|pixel| scale_pixel(*pixel, scale)).collect();
+
+        estimate_current(&scaled, model)
+    }
+
+    #[test]
+    fn full_white_frame() {
+        let model = PowerModel::WS2812B;
+        assert_eq!(estimate_current(&[0xFFFFFF; 12], &model), 732);
+        assert_eq!(estimate_current(&[0; 12], &model), 12);
+
+        for leds in [12, 60, 144] {
+            let frame = vec![0xFFFFFF; leds];
+            let requested = estimate_current(&frame, &model);
+
+            for budget in [leds as u32, 100, 250, 500, 1000, 2000, 4000] {
+                let current = scaled_current(&frame, &model, budget.max(leds as u32));
+                assert!(current <= budget.max(leds as u32), "{} LEDs at {}mA drew {}mA", leds, budget, current);
+                if budget >= requested {
+                    assert_eq!(current, requested, "{} LEDs at {}mA", leds, budget);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn never_over_budget_at_any_level() {
+        let models = [PowerModel::WS2812B, PowerModel::SK6812_RGBW];
+
+        for model in models {
+            for leds in [1, 12, 60, 144] {
+                for level in 1..=255 {
+                    let frame = vec![(level << 24) | (level << 16) | (level << 8) | level; leds];
+                    let requested = estimate_current(&frame, &model);
+
+                    for budget in (model.idle * leds as u32..requested).step_by(7) {
+                        let current = scaled_current(&frame, &model, budget);
+                        assert!(current <= budget, "{} LEDs at level {} and {}mA drew {}mA", leds, level, budget, current);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn budget_scale_edges() {
+        assert_eq!(budget_scale(500, 12, 500), 255);
+        assert_eq!(budget_scale(500, 12, 1000), 255);
+        // Only idle current, nothing to dim
+        assert_eq!(budget_scale(12, 12, 6), 255);
+        // Budget below idle turns the colors off
+        assert_eq!(budget_scale(500, 12, 6), 0);
+        // Half the color current, 127 less the rounding margin
+        assert_eq!(budget_scale(512, 12, 262), 126);
+    }
+
+    #[test]
+    fn scale_pixel_keeps_white() {
+        assert_eq!(scale_pixel(0xFF_FFFFFF, 255), 0xFF_FFFFFF);
+        assert_eq!(scale_pixel(0x80_FF4000, 127), 0x40_7F2000);
+        assert_eq!(scale_pixel(0xFF_FFFFFF, 0), 0);
+    }
+}
@@ -0,0 +1,13 @@
+// Hardware independent parts of the firmware, split out of the binary so they can be unit tested
+// on the host with `cargo test-host` (see .cargo/config.toml)
+#![cfg_attr(not(test), no_std)]
+#![allow(unused)] // Same as main.rs, take this out with it
+
+pub mod spi_config;
+pub mod ws2812b_timing;
+pub mod math;
+pub mod pixel_format;
+pub mod animation;
+pub mod compact_encoding;
+pub mod led_matrix;
+pub mod led_power;
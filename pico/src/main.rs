@@ -9,15 +9,15 @@ use core::str;
 // Core libraries
 use defmt::{info, panic};
 use embassy_executor::Spawner;
-use embassy_rp::{peripherals::{PIN_23, PIN_25}, spi::Polarity};
-use embassy_time::{Duration, Timer};
+use embassy_rp::peripherals::{PIN_23, PIN_25};
+use embassy_time::{Duration, Instant, Timer};
 use {defmt_rtt as _, panic_probe as _};
 
 // Pwm libraries
 // use embassy_rp::pwm::{Config, Pwm};
 
 // SPI libraries
-use embassy_rp::spi::Spi;
+use rp2040_pac::SPI1;
 // use embassy_rp::spi;
 
 // Wifi libraries
@@ -36,19 +36,41 @@ use embassy_usb::driver::EndpointError;
 use static_cell::StaticCell;
 
 // Custom modules
+// Shared with the host tested library
+use planterpi::{animation, compact_encoding, led_matrix, led_power, math, pixel_format, spi_config, ws2812b_timing};
+
 mod ws2812b;
-mod math;
+mod ws2812b_pio;
+mod ws2812b_compact;
+mod math_bench;
 mod pwm;
 mod gpio;
 mod spi;
+mod spi_bus;
+mod spi_trace;
+mod rgb_led;
+mod dma;
+mod pump;
+mod apa102;
+mod grow_light;
 
 // Custom libraries
+use animation::{Animator, Rainbow};
+use apa102::Apa102Driver;
 use gpio::{CtrlStatus::*, GPIODriver};
+use led_power::PowerModel;
 use pwm::PWMDriver;
-use spi::{SPIDriver, SPIFormat, SPISelector};
+use rgb_led::{LedPolarity, RgbLed};
+use spi::{SPIDriver, SPIFormat, SPIMode, SPIRole, SpiConfig};
+use ws2812b::{LedStrip, PixelFormat, SpiLedDriver};
+use ws2812b_compact::{BitEncoding, CompactSpiLedDriver};
+use ws2812b_pio::{PioLedDriver, PioSelector};
+use ws2812b_timing::LedChip;
 
 bind_interrupts!(struct Irqs {
     USBCTRL_IRQ => InterruptHandler<USB>;
+    DMA_IRQ_1 => dma::InterruptHandler;
+    SPI1_IRQ => spi::InterruptHandler<SPI1>;
 });
 
 // #[embassy_executor::task]
@@ -97,6 +119,15 @@ const BLUE_LED: usize = 5;
 
 const SPI1_SCK: usize = 10;
 const SPI1_MOSI: usize = 11;
+const SPI1_DMA_TX: usize = 1;
+const SPI1_DMA_RX: usize = 2;
+const PIO_DMA_CH: usize = 3;
+// 12, 60 or 144 depending on the planter
+const STRIP_LEDS: usize = 12;
+// What the strip may pull from VBUS, leaves room for the pico on a 500mA USB port
+const STRIP_POWER_BUDGET_MA: u32 = 400;
+// Logs float vs fixed point math timings once the serial logger is up
+const RUN_MATH_BENCHMARK: bool = false;
 
 #[embassy_executor::main]
 async fn main(_spawner: Spawner) {
@@ -165,6 +196,15 @@ async fn main(_spawner: Spawner) {
         CdcAcmClass::new(&mut builder, logger_state, 64)
     };
 
+    // Second serial port for the SPI trace, anything written to it gets the trace sent back
+    // (see tools/decode_spi_trace.py)
+    #[cfg(feature = "spi-trace")]
+    let mut trace_class = {
+        static STATE: StaticCell<State> = StaticCell::new();
+        let trace_state = STATE.init(State::new());
+        CdcAcmClass::new(&mut builder, trace_state, 64)
+    };
+
     // Creates the logger and returns the logger future
     // Note: You'll need to use log::info! afterwards instead of info! for this to work (this also applies to all the other log::* macros)
     let log_fut = embassy_usb_logger::with_class!(1024, log::LevelFilter::Info, logger_class);
@@ -198,23 +238,32 @@ async fn main(_spawner: Spawner) {
     // Enable the gpio driver
     let gpio_driver = GPIODriver::begin();
     let pwm_driver = PWMDriver::begin();
-    let spi_driver = SPIDriver::begin();
-
-    gpio_driver.set_pin(RED_LED, Pwm);
-    gpio_driver.set_pin(GREEN_LED, Pwm);
-    gpio_driver.set_pin(BLUE_LED, Pwm);
+    let led_spi = SPIDriver::<SPI1>::begin(Irqs);
 
     gpio_driver.set_pin(SPI1_SCK, Spi);
     gpio_driver.set_pin(SPI1_MOSI, Spi);
 
-    pwm_driver.start_pwm(RED_LED);
-    pwm_driver.start_pwm(GREEN_LED);
-    pwm_driver.start_pwm(BLUE_LED);
-
-    // spi_driver.set_baud_rate(125_000_000, 115200, SPISelector::Spi1);
+    let mut status_led = RgbLed::new(&pwm_driver, &gpio_driver, RED_LED, GREEN_LED, BLUE_LED, LedPolarity::CommonAnode);
+
+    led_spi.set_dma_channels(SPI1_DMA_TX, SPI1_DMA_RX);
+    let spi_led_driver: SpiLedDriver<_, STRIP_LEDS> = SpiLedDriver::new(&led_spi, LedChip::Ws2812b, PixelFormat::Grb).unwrap();
+    let timing = spi_led_driver.timing();
+    log::info!("LED SPI: {}Hz, {} bit frames, off by at most {}ns", timing.baudrate, timing.frame_bits, timing.max_error);
+    let mut led_strip: LedStrip<_, STRIP_LEDS> = LedStrip::new(spi_led_driver);
+    led_strip.set_power_budget(PowerModel::WS2812B, STRIP_POWER_BUDGET_MA);
+    // Or with 3 SPI bits per LED bit, this reconfigures SPI1 for the encoding
+    // let compact_driver: CompactSpiLedDriver<_, STRIP_LEDS> = CompactSpiLedDriver::new(&led_spi, PixelFormat::Grb, BitEncoding::ThreeBit).unwrap();
+    // let mut led_strip: LedStrip<_, STRIP_LEDS> = LedStrip::new(compact_driver);
+    // Or an APA102 / SK9822 strip on SPI1 (SCK and MOSI)
+    // let mut led_strip: LedStrip<_, STRIP_LEDS> = LedStrip::new(Apa102Driver::new(&led_spi, 4_000_000, PixelFormat::Bgr).unwrap());
+    // Or drive the strip from a PIO state machine instead of SPI1
+    // let mut led_strip: LedStrip<_, STRIP_LEDS> = LedStrip::new(PioLedDriver::new(PioSelector::Pio0, 0, SPI1_MOSI, PIO_DMA_CH, PixelFormat::Grb, Irqs));
 
     let mut hue = 0.0;
-    let hue_diff = 360 / ws2812b::NUM_LEDS;
+    let mut rainbow = Rainbow::new(200.0, 360.0);
+    let mut animator: Animator<STRIP_LEDS> = Animator::new(&mut rainbow);
+    let mut last_frame = Instant::now();
+    let mut last_report = Instant::now();
 
     // Debugging ---------------
 
@@ -230,55 +279,38 @@ async fn main(_spawner: Spawner) {
      */
     // Putting the loop in an asynchronous function lets us run the loop and the logger at the same time
     let echo_fut = async {
+        if RUN_MATH_BENCHMARK {
+            // Give the host time to open the serial port so the results aren't lost
+            Timer::after_secs(2).await;
+            math_bench::run();
+        }
+
         loop {
             if hue >= 360.0 {
                 hue %= 360.0;
             }
 
             // log::info!("Hue: {}", hue);
-            let rgb = math::color_math::u32_to_rgb(math::color_math::hsl_to_rgb(hue, 1.0, 0.5));
-            // log::info!("Red:   {}\n\rGreen: {}\n\rBlue:  {}", rgb.0 as i32, rgb.1, rgb.2);
-            pwm_driver.set_pwm_value_percent(RED_LED, math::map32(rgb.0 as f32, 0.0, 255.0, 1.0, 0.0));
-            pwm_driver.set_pwm_value_percent(GREEN_LED, math::map32(rgb.1 as f32, 0.0, 255.0, 1.0, 0.0));
-            pwm_driver.set_pwm_value_percent(BLUE_LED, math::map32(rgb.2 as f32, 0.0, 255.0, 1.0, 0.0));
+            status_led.set_hsl(hue, 1.0, 0.5);
 
 
-            let mut color_buffer: [u32; ws2812b::NUM_LEDS] = [0; ws2812b::NUM_LEDS];
+            let now = Instant::now();
+            animator.render(now - last_frame, led_strip.pixels_mut());
+            last_frame = now;
 
-            for i in 0..ws2812b::NUM_LEDS {
-                let color: f32 = ((hue as usize + (i * hue_diff)) % 360) as f32;
-                let rgb_full = math::color_math::hsl_to_rgb(color, 1.0, 0.5);
-                // log::info!("Color: {:#06x}", rgb);
-                color_buffer[i] = rgb_full;
+            if let Err(error) = led_strip.show().await {
+                log::warn!("LED strip write failed: {:?}", error);
             }
 
-            log::info!("{:#010x}\n\r{:#010x}\n\r{:#010x}\n\r{:#010x}\n\r{:#010x}\n\r{:#010x}\n\r{:#010x}\n\r{:#010x}\n\r{:#010x}\n\r{:#010x}\n\r{:#010x}\n\r{:#010x}",
-                color_buffer[0],
-                color_buffer[1],
-                color_buffer[2],
-                color_buffer[3],
-                color_buffer[4],
-                color_buffer[5],
-                color_buffer[6],
-                color_buffer[7],
-                color_buffer[8],
-                color_buffer[9],
-                color_buffer[10],
-                color_buffer[11],
-            );
-
-            let vals = spi_driver.set_baud_rate(125_000_000, 28000, SPISelector::Spi1);
-            spi_driver.send_data::<{ws2812b::LED_INFO_SIZE}>(
-                7,
-                &ws2812b::generate_addressable_led_buffer::<{ws2812b::LED_INFO_SIZE}>(&color_buffer), 
-                SPISelector::Spi1,
-                SPIFormat::Microwire
-            );
+            if last_report.elapsed() >= Duration::from_secs(1) {
+                log::info!("LED strip: {}mA (asked for {}mA)", led_strip.current(), led_strip.requested_current());
+                last_report = Instant::now();
+            }
             // log::info!("Prescale: {}\n\rPostdiv: {}", vals.0, vals.1);
             // log::info!("Freq = {}", (125_000_000 / (vals.0 as u32 * (1 + vals.1 as u32))));
 
-            // spi_driver.read_registers(SPISelector::Spi1);
-            // spi_driver.send_data::<2>(15, &[0xCAFE, 0xBABE], SPISelector::Spi1);
+            // led_spi.read_registers();
+            // led_spi.write(&[0xCAFE, 0xBABE]);
             
             // log::info!("System clock reg: {:#010x}", clocks.clk_sys_ctrl().read().bits());
             // log::info!("Periph clock reg: {:#010x}", clocks.clk_peri_ctrl().read().bits());
@@ -286,15 +318,33 @@ async fn main(_spawner: Spawner) {
             Timer::after_millis(5).await;
             hue += 1.0;
 
-            // let buf = ws2812b::generate_addressable_led_buffer::<{ ws2812b::LED_INFO_SIZE }>(&color_buffer);
+            // let buf = ws2812b::generate_addressable_led_buffer::<{ ws2812b::buffer_size(STRIP_LEDS) }>(&color_buffer, timing);
             // set_rgb(color_buffer[0]);
             // spi0.blocking_write(&buf).unwrap();
         }
     };
 
+    #[cfg(feature = "spi-trace")]
+    let trace_fut = async {
+        spi_trace::enable(true);
+
+        loop {
+            trace_class.wait_connection().await;
+
+            let mut request = [0u8; 64];
+            while trace_class.read_packet(&mut request).await.is_ok() {
+                if spi_trace::dump_usb(&mut trace_class).await.is_err() {
+                    break;
+                }
+            }
+        }
+    };
+    #[cfg(not(feature = "spi-trace"))]
+    let trace_fut = async {};
+
     // Run everything concurrently.
     // If we had made everything `'static` above instead, we could do this using separate tasks instead.
-    join(usb_fut, join(echo_fut, log_fut)).await;
+    join(usb_fut, join(echo_fut, join(log_fut, trace_fut))).await;
 
     /*
         Loop Section End
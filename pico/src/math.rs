@@ -66,6 +66,50 @@ pub mod color_math {
     }
 
 
+    // Same as hsl_to_rgb but from hue, saturation and value
+    pub fn hsv_to_rgb(hue: f32, saturation: f32, value: f32) -> u32 {
+        let chroma = value * saturation;
+        let hue_prime = hue / 60.0;
+        let x = chroma * (1.0 - abs32(hue_prime % 2.0 - 1.0));
+        let (red, green, blue) = hue_to_rgb(chroma, x, hue_prime);
+        let m = value - chroma;
+
+        rgb_to_u32(((red + m) * 255.0) as u8, ((green + m) * 255.0) as u8, ((blue + m) * 255.0) as u8)
+    }
+
+    // Gamma 2.8 lookup table so that perceived brightness is roughly linear with the input
+    const GAMMA_TABLE: [u8; 256] = [
+          0,   0,   0,   0,   0,   0,   0,   0,   0,   0,   0,   0,   0,   0,   0,   0,
+          0,   0,   0,   0,   0,   0,   0,   0,   0,   0,   0,   0,   1,   1,   1,   1,
+          1,   1,   1,   1,   1,   1,   1,   1,   1,   2,   2,   2,   2,   2,   2,   2,
+          2,   3,   3,   3,   3,   3,   3,   3,   4,   4,   4,   4,   4,   5,   5,   5,
+          5,   6,   6,   6,   6,   7,   7,   7,   7,   8,   8,   8,   9,   9,   9,  10,
+         10,  10,  11,  11,  11,  12,  12,  13,  13,  13,  14,  14,  15,  15,  16,  16,
+         17,  17,  18,  18,  19,  19,  20,  20,  21,  21,  22,  22,  23,  24,  24,  25,
+         25,  26,  27,  27,  28,  29,  29,  30,  31,  32,  32,  33,  34,  35,  35,  36,
+         37,  38,  39,  39,  40,  41,  42,  43,  44,  45,  46,  47,  48,  49,  50,  50,
+         51,  52,  54,  55,  56,  57,  58,  59,  60,  61,  62,  63,  64,  66,  67,  68,
+         69,  70,  72,  73,  74,  75,  77,  78,  79,  81,  82,  83,  85,  86,  87,  89,
+         90,  92,  93,  95,  96,  98,  99, 101, 102, 104, 105, 107, 109, 110, 112, 114,
+        115, 117, 119, 120, 122, 124, 126, 127, 129, 131, 133, 135, 137, 138, 140, 142,
+        144, 146, 148, 150, 152, 154, 156, 158, 160, 162, 164, 167, 169, 171, 173, 175,
+        177, 180, 182, 184, 186, 189, 191, 193, 196, 198, 200, 203, 205, 208, 210, 213,
+        215, 218, 220, 223, 225, 228, 231, 233, 236, 239, 241, 244, 247, 249, 252, 255,
+    ];
+
+    pub fn gamma_correct(value: u8) -> u8 {
+        GAMMA_TABLE[value as usize]
+    }
+
+    pub fn rgb_to_u32(red: u8, green: u8, blue: u8) -> u32 {
+        ((red as u32) << 16) | ((green as u32) << 8) | (blue as u32)
+    }
+
+    // Scales a single 8 bit channel by another 8 bit value (255 = unchanged)
+    pub fn scale8(value: u8, scale: u8) -> u8 {
+        ((value as u16 * (scale as u16 + 1)) >> 8) as u8
+    }
+
     pub fn u32_to_rgb(rgb: u32) -> (u8, u8, u8) {
         let red =   ((rgb & 0xFF0000) >> 16) as u8;
         let green = ((rgb & 0x00FF00) >> 8) as u8;
@@ -73,4 +117,219 @@ pub mod color_math {
         
         (red, green, blue)
     }
-}
\ No newline at end of file
+
+    // Scales all three channels of a 0xRRGGBB color (255 = unchanged)
+    pub fn scale_color(color: u32, scale: u8) -> u32 {
+        let (red, green, blue) = u32_to_rgb(color);
+        rgb_to_u32(scale8(red, scale), scale8(green, scale), scale8(blue, scale))
+    }
+
+    // Mixes two colors channel by channel, amount 0 gives from and 255 gives to
+    pub fn blend(from: u32, to: u32, amount: u8) -> u32 {
+        let (from_red, from_green, from_blue) = u32_to_rgb(from);
+        let (to_red, to_green, to_blue) = u32_to_rgb(to);
+        let mix = |from: u8, to: u8| scale8(from, 255 - amount).saturating_add(scale8(to, amount));
+
+        rgb_to_u32(mix(from_red, to_red), mix(from_green, to_green), mix(from_blue, to_blue))
+    }
+}
+
+// Fixed point versions of map32 and the color math, the RP2040 has no FPU so floats are done in software
+//  Q16.16: i32 with 16 fraction bits, used for hue in degrees and for map / lerp
+//  Q8.8: u16 with 8 fraction bits (256 = 1.0), used for saturation, luminance and value
+// The colors come out within 1 of the float versions on every channel
+pub mod fixed {
+    pub type Q16 = i32;
+    pub type Q8 = u16;
+
+    pub const Q16_ONE: Q16 = 1 << 16;
+    pub const Q8_ONE: Q8 = 1 << 8;
+
+    pub fn to_q16(value: f32) -> Q16 {
+        (value * Q16_ONE as f32) as Q16
+    }
+
+    pub fn from_q16(value: Q16) -> f32 {
+        value as f32 / Q16_ONE as f32
+    }
+
+    pub fn to_q8(value: f32) -> Q8 {
+        (value.clamp(0.0, 255.0) * Q8_ONE as f32) as Q8
+    }
+
+    pub fn map_q16(input: Q16, input_start: Q16, input_end: Q16, output_start: Q16, output_end: Q16) -> Q16 {
+        let span = input_end as i64 - input_start as i64;
+        if span == 0 {
+            return output_start;
+        }
+
+        let offset = ((input as i64 - input_start as i64) * (output_end as i64 - output_start as i64)) / span;
+        (output_start as i64 + offset) as Q16
+    }
+
+    // t goes from 0 (a) to Q16_ONE (b)
+    pub fn lerp_q16(a: Q16, b: Q16, t: Q16) -> Q16 {
+        a + (((b as i64 - a as i64) * t as i64) >> 16) as Q16
+    }
+
+    // For color channels, t goes from 0 (a) to 255 (b)
+    pub fn lerp8(a: u8, b: u8, t: u8) -> u8 {
+        (a as i32 + (((b as i32 - a as i32) * t as i32) / 255)) as u8
+    }
+
+    // hue in degrees (wraps at 360), saturation and luminance from 0 to Q8_ONE
+    pub fn hsl_to_rgb(hue: Q16, saturation: Q8, luminance: Q8) -> u32 {
+        let saturation = (saturation.min(Q8_ONE) as i32) << 8;
+        let luminance = (luminance.min(Q8_ONE) as i32) << 8;
+        let chroma = (((Q16_ONE - (2 * luminance - Q16_ONE).abs()) as i64 * saturation as i64) >> 16) as i32;
+
+        hue_to_rgb(hue, chroma, luminance - (chroma / 2))
+    }
+
+    pub fn hsv_to_rgb(hue: Q16, saturation: Q8, value: Q8) -> u32 {
+        let saturation = (saturation.min(Q8_ONE) as i32) << 8;
+        let value = (value.min(Q8_ONE) as i32) << 8;
+        let chroma = ((value as i64 * saturation as i64) >> 16) as i32;
+
+        hue_to_rgb(hue, chroma, value - chroma)
+    }
+
+    // chroma and m are Q16.16
+    fn hue_to_rgb(hue: Q16, chroma: i32, m: i32) -> u32 {
+        let hue_prime = hue.rem_euclid(360 * Q16_ONE) / 60;
+        let distance = ((hue_prime % (2 * Q16_ONE)) - Q16_ONE).abs();
+        let x = ((chroma as i64 * (Q16_ONE - distance) as i64) >> 16) as i32;
+
+        let (red, green, blue) = match hue_prime >> 16 {
+            0 => (chroma, x, 0),
+            1 => (x, chroma, 0),
+            2 => (0, chroma, x),
+            3 => (0, x, chroma),
+            4 => (x, 0, chroma),
+            _ => (chroma, 0, x),
+        };
+
+        (to_channel(red + m) << 16) | (to_channel(green + m) << 8) | to_channel(blue + m)
+    }
+
+    fn to_channel(value: i32) -> u32 {
+        ((value.clamp(0, Q16_ONE) as i64 * 255) >> 16) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every 1/16, exact in both Q8.8 and f32 so only the math itself gets compared
+    fn sixteenths() -> impl Iterator<Item = f32> {
+        (0..=16).map(|step| step as f32 / 16.0)
+    }
+
+    // Half degree steps all the way around, plus a lap past 360 and some negative hues
+    fn hues() -> impl Iterator<Item = f32> {
+        (-90..=900).map(|step| step as f32 / 2.0)
+    }
+
+    fn assert_channels_within_one(fixed: u32, float: u32, context: &str) {
+        let (fixed_red, fixed_green, fixed_blue) = color_math::u32_to_rgb(fixed);
+        let (float_red, float_green, float_blue) = color_math::u32_to_rgb(float);
+
+        assert!(fixed_red.abs_diff(float_red) <= 1, "red {:06X} vs {:06X} {}", fixed, float, context);
+        assert!(fixed_green.abs_diff(float_green) <= 1, "green {:06X} vs {:06X} {}", fixed, float, context);
+        assert!(fixed_blue.abs_diff(float_blue) <= 1, "blue {:06X} vs {:06X} {}", fixed, float, context);
+    }
+
+    #[test]
+    fn fixed_hsl_matches_float() {
+        for hue in hues() {
+            for saturation in sixteenths() {
+                for luminance in sixteenths() {
+                    let fixed = fixed::hsl_to_rgb(fixed::to_q16(hue), fixed::to_q8(saturation), fixed::to_q8(luminance));
+                    let float = color_math::hsl_to_rgb(hue.rem_euclid(360.0), saturation, luminance);
+                    assert_channels_within_one(fixed, float, &format!("at hsl({}, {}, {})", hue, saturation, luminance));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fixed_hsv_matches_float() {
+        for hue in hues() {
+            for saturation in sixteenths() {
+                for value in sixteenths() {
+                    let fixed = fixed::hsv_to_rgb(fixed::to_q16(hue), fixed::to_q8(saturation), fixed::to_q8(value));
+                    let float = color_math::hsv_to_rgb(hue.rem_euclid(360.0), saturation, value);
+                    assert_channels_within_one(fixed, float, &format!("at hsv({}, {}, {})", hue, saturation, value));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fixed_map_matches_float() {
+        // Eighths from -4 to 4, the ranges include reversed and negative ones
+        let values: Vec<f32> = (-32..=32).map(|step| step as f32 / 8.0).collect();
+        let ranges = [(0.0, 1.0), (-1.0, 3.0), (2.0, -2.0), (0.0, 0.125), (-4.0, -3.0)];
+
+        for (input_start, input_end) in ranges {
+            for (output_start, output_end) in ranges {
+                for &input in values.iter() {
+                    let fixed = fixed::map_q16(
+                        fixed::to_q16(input),
+                        fixed::to_q16(input_start),
+                        fixed::to_q16(input_end),
+                        fixed::to_q16(output_start),
+                        fixed::to_q16(output_end)
+                    );
+                    let float = fixed::to_q16(map32(input, input_start, input_end, output_start, output_end));
+                    assert!(
+                        fixed.abs_diff(float) <= 1,
+                        "{} from {}..{} to {}..{}: {} vs {}", input, input_start, input_end, output_start, output_end, fixed, float
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fixed_lerp_matches_float() {
+        let ends = [-3.0, -0.5, 0.0, 0.375, 1.0, 2.5];
+
+        for a in ends {
+            for b in ends {
+                for step in 0..=64 {
+                    let t = step as f32 / 64.0;
+                    let fixed = fixed::lerp_q16(fixed::to_q16(a), fixed::to_q16(b), fixed::to_q16(t));
+                    let float = fixed::to_q16(map32(t, 0.0, 1.0, a, b));
+                    assert!(fixed.abs_diff(float) <= 1, "{} to {} at {}: {} vs {}", a, b, t, fixed, float);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn lerp8_matches_float() {
+        for a in (0..=255).step_by(15) {
+            for b in (0..=255).step_by(15) {
+                for t in 0..=255 {
+                    let float = map32(t as f32, 0.0, 255.0, a as f32, b as f32) as u8;
+                    let fixed = fixed::lerp8(a, b, t);
+                    assert!(fixed.abs_diff(float) <= 1, "{} to {} at {}: {} vs {}", a, b, t, fixed, float);
+                }
+
+                assert_eq!(fixed::lerp8(a, b, 0), a);
+                assert_eq!(fixed::lerp8(a, b, 255), b);
+            }
+        }
+    }
+
+    #[test]
+    fn q_conversions_round_trip() {
+        assert_eq!(fixed::to_q16(1.0), fixed::Q16_ONE);
+        assert_eq!(fixed::to_q16(-2.5), -5 * fixed::Q16_ONE / 2);
+        assert_eq!(fixed::from_q16(fixed::to_q16(123.25)), 123.25);
+        assert_eq!(fixed::to_q8(1.0), fixed::Q8_ONE);
+        assert_eq!(fixed::to_q8(-1.0), 0);
+    }
+}
@@ -0,0 +1,75 @@
+use core::hint::black_box;
+
+use cortex_m::peripheral::syst::SystClkSource;
+use cortex_m::peripheral::SYST;
+
+use crate::math::{color_math, fixed, map32};
+
+const ITERATIONS: u32 = 100;
+// SysTick only counts 24 bits
+const SYST_MASK: u32 = 0x00FF_FFFF;
+
+// Average core cycles per call, the loop overhead is measured with an empty body and taken off
+fn cycles_per_call(mut body: impl FnMut(u32)) -> u32 {
+    let measure = |body: &mut dyn FnMut(u32)| {
+        let start = SYST::get_current();
+        for i in 0..ITERATIONS {
+            body(black_box(i));
+        }
+        start.wrapping_sub(SYST::get_current()) & SYST_MASK
+    };
+
+    let overhead = measure(&mut |i| {
+        black_box(i);
+    });
+    let total = measure(&mut body);
+
+    total.saturating_sub(overhead) / ITERATIONS
+}
+
+// Times the float math against the fixed point versions and logs the cycle counts
+// SysTick isn't used by embassy (the time driver runs off TIMER) so it's free to take over here
+pub fn run() {
+    let mut syst = unsafe { cortex_m::Peripherals::steal() }.SYST;
+    syst.set_clock_source(SystClkSource::Core);
+    syst.set_reload(SYST_MASK);
+    syst.clear_current();
+    syst.enable_counter();
+
+    let map_float = cycles_per_call(|i| {
+        black_box(map32(i as f32, 0.0, 100.0, 0.0, 255.0));
+    });
+    let map_fixed = cycles_per_call(|i| {
+        black_box(fixed::map_q16((i as i32) << 16, 0, 100 << 16, 0, 255 << 16));
+    });
+
+    let lerp_float = cycles_per_call(|i| {
+        let t = i as f32 / ITERATIONS as f32;
+        black_box(10.0 + ((200.0 - 10.0) * t));
+    });
+    let lerp_fixed = cycles_per_call(|i| {
+        black_box(fixed::lerp_q16(10 << 16, 200 << 16, ((i << 16) / ITERATIONS) as i32));
+    });
+
+    let hsl_float = cycles_per_call(|i| {
+        black_box(color_math::hsl_to_rgb((i * 3) as f32, 1.0, 0.5));
+    });
+    let hsl_fixed = cycles_per_call(|i| {
+        black_box(fixed::hsl_to_rgb(((i * 3) as i32) << 16, fixed::Q8_ONE, fixed::Q8_ONE / 2));
+    });
+
+    let hsv_float = cycles_per_call(|i| {
+        black_box(color_math::hsv_to_rgb((i * 3) as f32, 1.0, 1.0));
+    });
+    let hsv_fixed = cycles_per_call(|i| {
+        black_box(fixed::hsv_to_rgb(((i * 3) as i32) << 16, fixed::Q8_ONE, fixed::Q8_ONE));
+    });
+
+    syst.disable_counter();
+
+    log::info!("Cycles per call (float / fixed):");
+    log::info!("  map: {} / {}", map_float, map_fixed);
+    log::info!("  lerp: {} / {}", lerp_float, lerp_fixed);
+    log::info!("  hsl_to_rgb: {} / {}", hsl_float, hsl_fixed);
+    log::info!("  hsv_to_rgb: {} / {}", hsv_float, hsv_fixed);
+}
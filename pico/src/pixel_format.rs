@@ -0,0 +1,169 @@
+use crate::math::color_math;
+use crate::ws2812b_timing::SpiLedTiming;
+
+// Order the channels go out on the wire, pixels are always 0xWWRRGGBB in memory
+// (the white byte is ignored by the 3 channel formats)
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PixelFormat {
+    Rgb,
+    Rbg,
+    Grb, // WS2812B
+    Gbr,
+    Brg,
+    Bgr,
+    // 4 channel parts like the SK6812, extract_white moves the part all three colors share onto the white LED
+    Rgbw { extract_white: bool },
+    Grbw { extract_white: bool },
+}
+
+impl PixelFormat {
+    pub fn channels(&self) -> usize {
+        match self {
+            PixelFormat::Rgbw { .. } | PixelFormat::Grbw { .. } => 4,
+            _ => 3,
+        }
+    }
+
+    // Channel values in wire order, only the first channels() are sent
+    pub fn wire_bytes(&self, color: u32) -> [u8; 4] {
+        let (mut red, mut green, mut blue) = color_math::u32_to_rgb(color);
+        let mut white = (color >> 24) as u8;
+
+        if let PixelFormat::Rgbw { extract_white: true } | PixelFormat::Grbw { extract_white: true } = self {
+            let shared = red.min(green).min(blue);
+            red -= shared;
+            green -= shared;
+            blue -= shared;
+            white = white.saturating_add(shared);
+        }
+
+        match self {
+            PixelFormat::Rgb => [red, green, blue, 0],
+            PixelFormat::Rbg => [red, blue, green, 0],
+            PixelFormat::Grb => [green, red, blue, 0],
+            PixelFormat::Gbr => [green, blue, red, 0],
+            PixelFormat::Brg => [blue, red, green, 0],
+            PixelFormat::Bgr => [blue, green, red, 0],
+            PixelFormat::Rgbw { .. } => [red, green, blue, white],
+            PixelFormat::Grbw { .. } => [green, red, blue, white],
+        }
+    }
+}
+
+// Writes the logic words for one pixel (8 per channel, MSB first) and returns how many were written
+pub fn encode_pixel(color: u32, format: PixelFormat, timing: &SpiLedTiming, output: &mut [u16]) -> usize {
+    let channels = format.channels();
+    let bytes = format.wire_bytes(color);
+
+    for (byte, words) in bytes[..channels].iter().zip(output.chunks_mut(8)) {
+        for (bit, word) in words.iter_mut().enumerate() {
+            *word = if byte & (0x80 >> bit) != 0 { timing.logic_1 } else { timing.logic_0 };
+        }
+    }
+
+    channels * 8
+}
+
+// Frame buffers kept as one fixed size array per pixel, viewed as the bytes that go out
+pub fn frame_bytes<const PIXEL_BYTES: usize>(frame: &[[u8; PIXEL_BYTES]]) -> &[u8] {
+    // Nested byte arrays have no padding, the pixels are packed together from the start
+    unsafe {
+        core::slice::from_raw_parts(frame.as_ptr() as *const u8, PIXEL_BYTES * frame.len())
+    }
+}
+
+pub fn frame_bytes_mut<const PIXEL_BYTES: usize>(frame: &mut [[u8; PIXEL_BYTES]]) -> &mut [u8] {
+    unsafe {
+        core::slice::from_raw_parts_mut(frame.as_mut_ptr() as *mut u8, PIXEL_BYTES * frame.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ws2812b_timing::{calculate_spi_timing, LedChip};
+
+    const COLOR: u32 = 0x40_112233; // white 0x40, red 0x11, green 0x22, blue 0x33
+
+    #[test]
+    fn three_channel_orders() {
+        let expected = [
+            (PixelFormat::Rgb, [0x11, 0x22, 0x33]),
+            (PixelFormat::Rbg, [0x11, 0x33, 0x22]),
+            (PixelFormat::Grb, [0x22, 0x11, 0x33]),
+            (PixelFormat::Gbr, [0x22, 0x33, 0x11]),
+            (PixelFormat::Brg, [0x33, 0x11, 0x22]),
+            (PixelFormat::Bgr, [0x33, 0x22, 0x11]),
+        ];
+
+        for (format, bytes) in expected {
+            assert_eq!(format.channels(), 3, "{:?}", format);
+            // The white byte is dropped
+            assert_eq!(format.wire_bytes(COLOR), [bytes[0], bytes[1], bytes[2], 0], "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn four_channel_orders() {
+        for extract_white in [false, true] {
+            assert_eq!(PixelFormat::Rgbw { extract_white }.channels(), 4);
+            assert_eq!(PixelFormat::Grbw { extract_white }.channels(), 4);
+        }
+
+        assert_eq!(PixelFormat::Rgbw { extract_white: false }.wire_bytes(COLOR), [0x11, 0x22, 0x33, 0x40]);
+        assert_eq!(PixelFormat::Grbw { extract_white: false }.wire_bytes(COLOR), [0x22, 0x11, 0x33, 0x40]);
+    }
+
+    #[test]
+    fn extract_white_moves_the_shared_part() {
+        let rgbw = PixelFormat::Rgbw { extract_white: true };
+        assert_eq!(rgbw.wire_bytes(0x00FFFFFF), [0, 0, 0, 255]);
+        assert_eq!(rgbw.wire_bytes(COLOR), [0x00, 0x11, 0x22, 0x51]);
+        assert_eq!(PixelFormat::Grbw { extract_white: true }.wire_bytes(COLOR), [0x11, 0x00, 0x22, 0x51]);
+
+        // Nothing shared when a channel is off
+        assert_eq!(rgbw.wire_bytes(0x00FF8000), [0xFF, 0x80, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn extracted_white_saturates() {
+        let rgbw = PixelFormat::Rgbw { extract_white: true };
+        assert_eq!(rgbw.wire_bytes(0xC0_808080), [0, 0, 0, 255]);
+        assert_eq!(rgbw.wire_bytes(0xFF_FFFFFF), [0, 0, 0, 255]);
+        assert_eq!(rgbw.wire_bytes(0xF0_20FF20), [0x00, 0xDF, 0x00, 255]);
+    }
+
+    #[test]
+    fn frame_bytes_in_pixel_order() {
+        let mut frame = [[1, 2, 3], [4, 5, 6]];
+        assert_eq!(frame_bytes(&frame), [1, 2, 3, 4, 5, 6]);
+
+        frame_bytes_mut(&mut frame)[2..4].copy_from_slice(&[7, 8]);
+        assert_eq!(frame, [[1, 2, 7], [8, 5, 6]]);
+        assert_eq!(frame_bytes::<4>(&[]), []);
+    }
+
+    #[test]
+    fn encode_pixel_sends_bytes_msb_first() {
+        let timing = calculate_spi_timing(125_000_000, &LedChip::Ws2812b.timing()).unwrap();
+        let (zero, one) = (timing.logic_0, timing.logic_1);
+
+        let mut output = [0xAAAA; 40];
+        let used = encode_pixel(0x00_A5F00F, PixelFormat::Grb, &timing, &mut output);
+        assert_eq!(used, 24);
+        assert_eq!(output[..24], [
+            one, one, one, one, zero, zero, zero, zero, // green 0xF0
+            one, zero, one, zero, zero, one, zero, one, // red 0xA5
+            zero, zero, zero, zero, one, one, one, one, // blue 0x0F
+        ]);
+        // Nothing past the pixel is touched
+        assert!(output[24..].iter().all(|word| *word == 0xAAAA));
+
+        let used = encode_pixel(0x80_000001, PixelFormat::Rgbw { extract_white: false }, &timing, &mut output);
+        assert_eq!(used, 32);
+        assert_eq!(output[16..32], [
+            zero, zero, zero, zero, zero, zero, zero, one, // blue 0x01
+            one, zero, zero, zero, zero, zero, zero, zero, // white 0x80
+        ]);
+    }
+}
@@ -0,0 +1,199 @@
+use core::cell::Cell;
+
+use embassy_time::{Duration, Instant};
+
+use crate::gpio::{CtrlStatus, GPIODriver};
+use crate::pwm::PWMDriver;
+
+#[derive(Copy, Clone)]
+pub struct PumpConfig {
+    pub max_duty: f32,           // Never drive the pump above this (0.0 - 1.0)
+    pub ramp_time: Duration,     // Time to ramp from off to max_duty, smaller steps take proportionally less
+    pub max_on_time: Duration,   // Longest the pump can run in one go
+    pub min_off_time: Duration,  // Rest time required between runs
+    pub watchdog_timeout: Duration, // Pump stops if feed() isn't called within this time
+}
+
+impl Default for PumpConfig {
+    fn default() -> Self {
+        PumpConfig {
+            max_duty: 0.8,
+            ramp_time: Duration::from_millis(500),
+            max_on_time: Duration::from_secs(30),
+            min_off_time: Duration::from_secs(10),
+            watchdog_timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PumpState {
+    Off,
+    RampingUp,
+    Running,
+    Faulted(PumpError),
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PumpError {
+    CoolingDown,     // min_off_time since the last run hasn't passed yet
+    WatchdogExpired, // The controlling task stopped calling feed()
+    Faulted,         // The pump has to be cleared with clear_fault() before it can run again
+}
+
+// Uses Cells so the pump can be shared between the control loop and the update loop when joined
+pub struct Pump<'a> {
+    pwm_driver: &'a PWMDriver,
+    pin: usize,
+    config: PumpConfig,
+    state: Cell<PumpState>,
+    target_duty: Cell<f32>,
+    current_duty: Cell<f32>,
+    ramp_from: Cell<f32>,
+    ramp_started_at: Cell<Instant>,
+    started_at: Cell<Instant>,
+    stopped_at: Cell<Option<Instant>>,
+    last_feed: Cell<Instant>,
+}
+
+impl<'a> Pump<'a> {
+    pub fn new(pwm_driver: &'a PWMDriver, gpio_driver: &GPIODriver, pin: usize, config: PumpConfig) -> Self {
+        gpio_driver.set_pin(pin, CtrlStatus::Pwm);
+        pwm_driver.start_pwm(pin);
+        pwm_driver.set_pwm_value_percent(pin, 0.0);
+
+        Pump {
+            pwm_driver,
+            pin,
+            config,
+            state: Cell::new(PumpState::Off),
+            target_duty: Cell::new(0.0),
+            current_duty: Cell::new(0.0),
+            ramp_from: Cell::new(0.0),
+            ramp_started_at: Cell::new(Instant::now()),
+            started_at: Cell::new(Instant::now()),
+            stopped_at: Cell::new(None),
+            last_feed: Cell::new(Instant::now()),
+        }
+    }
+
+    // Starts ramping the pump towards duty (clamped to max_duty)
+    pub fn start(&self, duty: f32) -> Result<(), PumpError> {
+        match self.state.get() {
+            PumpState::Faulted(_) => return Err(PumpError::Faulted),
+            PumpState::RampingUp | PumpState::Running => {
+                self.set_target(duty);
+                self.feed();
+                return Ok(());
+            },
+            PumpState::Off => {},
+        }
+
+        if let Some(stopped_at) = self.stopped_at.get() {
+            if stopped_at.elapsed() < self.config.min_off_time {
+                return Err(PumpError::CoolingDown);
+            }
+        }
+
+        self.current_duty.set(0.0);
+        self.started_at.set(Instant::now());
+        self.last_feed.set(Instant::now());
+        self.set_target(duty);
+        Ok(())
+    }
+
+    // Stopping a faulted pump keeps the fault so it still has to be cleared
+    pub fn stop(&self) {
+        match self.state.get() {
+            PumpState::Faulted(error) => self.shut_off(PumpState::Faulted(error)),
+            _ => self.shut_off(PumpState::Off),
+        }
+    }
+
+    // Has to be called by the controlling task more often than watchdog_timeout while the pump runs
+    pub fn feed(&self) {
+        self.last_feed.set(Instant::now());
+    }
+
+    pub fn clear_fault(&self) {
+        if let PumpState::Faulted(_) = self.state.get() {
+            self.state.set(PumpState::Off);
+        }
+    }
+
+    pub fn state(&self) -> PumpState {
+        self.state.get()
+    }
+
+    pub fn duty(&self) -> f32 {
+        self.current_duty.get()
+    }
+
+    // Call this periodically (every few ms) to advance the ramp and enforce the limits
+    pub fn update(&self) -> PumpState {
+        match self.state.get() {
+            PumpState::Off | PumpState::Faulted(_) => {},
+            PumpState::RampingUp | PumpState::Running => {
+                if self.last_feed.get().elapsed() > self.config.watchdog_timeout {
+                    log::warn!("Pump on pin {} lost its watchdog, stopping", self.pin);
+                    self.shut_off(PumpState::Faulted(PumpError::WatchdogExpired));
+                } else if self.started_at.get().elapsed() > self.config.max_on_time {
+                    log::warn!("Pump on pin {} hit its max on time, stopping", self.pin);
+                    self.shut_off(PumpState::Off);
+                } else {
+                    self.ramp();
+                }
+            },
+        }
+
+        self.state.get()
+    }
+
+    // Lowering the duty takes effect right away, raising it goes through the ramp again
+    fn set_target(&self, duty: f32) {
+        let duty = self.clamp_duty(duty);
+        self.target_duty.set(duty);
+
+        if duty > self.current_duty.get() {
+            self.ramp_from.set(self.current_duty.get());
+            self.ramp_started_at.set(Instant::now());
+            self.state.set(PumpState::RampingUp);
+        }
+    }
+
+    fn ramp(&self) {
+        let target = self.target_duty.get();
+        let ramp_ms = self.config.ramp_time.as_millis();
+        let elapsed_ms = self.ramp_started_at.get().elapsed().as_millis();
+
+        // The slope is always max_duty per ramp_time so small steps don't ramp slower
+        let duty = if ramp_ms == 0 {
+            target
+        } else {
+            let slope = self.config.max_duty / ramp_ms as f32;
+            (self.ramp_from.get() + slope * elapsed_ms as f32).min(target)
+        };
+
+        if duty >= target {
+            self.state.set(PumpState::Running);
+        }
+
+        self.current_duty.set(duty);
+        self.pwm_driver.set_pwm_value_percent(self.pin, duty);
+    }
+
+    fn shut_off(&self, state: PumpState) {
+        self.pwm_driver.set_pwm_value_percent(self.pin, 0.0);
+        self.current_duty.set(0.0);
+        self.target_duty.set(0.0);
+
+        if self.state.get() != PumpState::Off {
+            self.stopped_at.set(Some(Instant::now()));
+        }
+        self.state.set(state);
+    }
+
+    fn clamp_duty(&self, duty: f32) -> f32 {
+        duty.clamp(0.0, self.config.max_duty.clamp(0.0, 1.0))
+    }
+}
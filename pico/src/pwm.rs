@@ -6,6 +6,7 @@ use crate::math;
 const DEFAULT_TOP: u16 = 0x8000;
 const DEFAULT_BOT: u16 = 0x0000;
 const NUM_PINS: usize = 30;
+const NUM_SLICES: usize = 8;
 
 // TODO: Review the publicity of everything
 
@@ -35,6 +36,24 @@ mod pwm_enums {
     }
 }
 
+// Snapshot of a PWM slice's registers with the values worked out
+#[derive(Copy, Clone, Debug)]
+pub struct SliceStatus {
+    pub slice: u8,
+    pub enabled: bool,
+    pub divider_int: u8,
+    pub divider_frac: u8,
+    pub top: u16,
+    pub compare_a: u16,
+    pub compare_b: u16,
+    pub frequency: f32, // Hz
+    pub duty_a: f32,    // Percent (0 - 100)
+    pub duty_b: f32,    // Percent (0 - 100)
+    pub phase_correct: bool,
+    pub invert_a: bool,
+    pub invert_b: bool,
+}
+
 #[derive(Copy, Clone)]
 pub struct PwmPin {
     id: u8,
@@ -115,14 +134,15 @@ impl PWMDriver {
             w.top().bits(DEFAULT_TOP)
         });
 
+        // A and B share the CC register, modify so the other pin on the slice keeps its level
         match (self.pins[pin].slice) {
             pwm_enums::PwmSlice::A => {
-                self.pwm.ch(channel).cc().write(|w| unsafe {
+                self.pwm.ch(channel).cc().modify(|_, w| unsafe {
                     w.a().bits(DEFAULT_BOT)
                 });
             },
             pwm_enums::PwmSlice::B => {
-                self.pwm.ch(channel).cc().write(|w| unsafe {
+                self.pwm.ch(channel).cc().modify(|_, w| unsafe {
                     w.b().bits(DEFAULT_BOT)
                 });
             },
@@ -141,12 +161,12 @@ impl PWMDriver {
 
         match (slice) {
             pwm_enums::PwmSlice::A => {
-                self.pwm.ch(channel).cc().write(|w| unsafe {
+                self.pwm.ch(channel).cc().modify(|_, w| unsafe {
                     w.a().bits(value)
                 });
             },
             pwm_enums::PwmSlice::B => {
-                self.pwm.ch(channel).cc().write(|w| unsafe {
+                self.pwm.ch(channel).cc().modify(|_, w| unsafe {
                     w.b().bits(value)
                 });
             },
@@ -162,23 +182,65 @@ impl PWMDriver {
 
         match (slice) {
             pwm_enums::PwmSlice::A => {
-                self.pwm.ch(channel).cc().write(|w| unsafe {
+                self.pwm.ch(channel).cc().modify(|_, w| unsafe {
                     w.a().bits(value)
                 });
             },
             pwm_enums::PwmSlice::B => {
-                self.pwm.ch(channel).cc().write(|w| unsafe {
+                self.pwm.ch(channel).cc().modify(|_, w| unsafe {
                     w.b().bits(value)
                 });
             },
         }
     }
 
-    // ! This one was for debugging purposes, make into better read function
-    #[deprecated]
-    pub fn read_info(&self, channel: usize) {
-        log::info!("Top Register:       {:#010x}", self.pwm.ch(channel).top().read().bits());
-        log::info!("Compare Register:   {:#010x}", self.pwm.ch(channel).cc().read().bits());
-        log::info!("Control Register:   {:#010x}", self.pwm.ch(channel).csr().read().bits());
+    // Returns the live state of the slice that drives this pin, None past GPIO29
+    pub fn pin_status(&self, pin: usize) -> Option<SliceStatus> {
+        self.slice_status(self.pins.get(pin)?.channel as usize)
     }
-}
\ No newline at end of file
+
+    // Slice here is the hardware slice (0 - 7), the same number as PwmChannel. None for anything higher
+    pub fn slice_status(&self, slice: usize) -> Option<SliceStatus> {
+        if slice >= NUM_SLICES {
+            return None;
+        }
+
+        let ch = self.pwm.ch(slice);
+        let csr = ch.csr().read();
+        let div = ch.div().read();
+        let top = ch.top().read().top().bits();
+        let cc = ch.cc().read();
+
+        let divider_int = div.int().bits();
+        let divider_frac = div.frac().bits();
+
+        // An integer part of 0 means divide by 256
+        let divider = if divider_int == 0 { 256.0 } else { divider_int as f32 } + (divider_frac as f32 / 16.0);
+        let phase_correct = csr.ph_correct().bit_is_set();
+        let period = (top as f32 + 1.0) * if phase_correct { 2.0 } else { 1.0 };
+
+        let compare_a = cc.a().bits();
+        let compare_b = cc.b().bits();
+
+        Some(SliceStatus {
+            slice: slice as u8,
+            enabled: csr.en().bit_is_set(),
+            divider_int,
+            divider_frac,
+            top,
+            compare_a,
+            compare_b,
+            frequency: embassy_rp::clocks::clk_sys_freq() as f32 / (divider * period),
+            duty_a: duty_percent(compare_a, top),
+            duty_b: duty_percent(compare_b, top),
+            phase_correct,
+            invert_a: csr.a_inv().bit_is_set(),
+            invert_b: csr.b_inv().bit_is_set(),
+        })
+    }
+}
+
+// Compare values above TOP keep the output high for the whole period
+fn duty_percent(compare: u16, top: u16) -> f32 {
+    (compare as f32 / (top as f32 + 1.0)).min(1.0) * 100.0
+}
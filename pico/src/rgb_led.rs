@@ -0,0 +1,181 @@
+use embassy_time::Instant;
+
+use crate::gpio::{CtrlStatus, GPIODriver};
+use crate::math::{self, color_math};
+use crate::pwm::PWMDriver;
+
+// Which side of the LED is shared between the three channels
+#[derive(Copy, Clone, PartialEq)]
+pub enum LedPolarity {
+    CommonAnode,    // Channel turns on when the pin is pulled low
+    CommonCathode,  // Channel turns on when the pin is driven high
+}
+
+#[derive(Copy, Clone)]
+pub enum LedPattern {
+    Solid,
+    Blink { period_ms: u64 },
+    Breathe { period_ms: u64 },
+}
+
+// Semantic states so applications don't have to pick colors themselves
+#[derive(Copy, Clone)]
+pub enum LedStatus {
+    Off,
+    Idle,
+    Ok,
+    Busy,
+    Warning,
+    Error,
+}
+
+pub struct RgbLed<'a> {
+    pwm_driver: &'a PWMDriver,
+    red_pin: usize,
+    green_pin: usize,
+    blue_pin: usize,
+    polarity: LedPolarity,
+    gamma: bool,
+    white_balance: (u8, u8, u8),
+    color: u32,
+    pattern: LedPattern,
+    pattern_start: Instant,
+}
+
+impl<'a> RgbLed<'a> {
+    // Sets the three pins to PWM and starts their slices
+    pub fn new(
+        pwm_driver: &'a PWMDriver,
+        gpio_driver: &GPIODriver,
+        red_pin: usize,
+        green_pin: usize,
+        blue_pin: usize,
+        polarity: LedPolarity
+    ) -> Self {
+        for pin in [red_pin, green_pin, blue_pin] {
+            gpio_driver.set_pin(pin, CtrlStatus::Pwm);
+            pwm_driver.start_pwm(pin);
+        }
+
+        let mut led = RgbLed {
+            pwm_driver,
+            red_pin,
+            green_pin,
+            blue_pin,
+            polarity,
+            gamma: true,
+            white_balance: (255, 255, 255),
+            color: 0,
+            pattern: LedPattern::Solid,
+            pattern_start: Instant::now(),
+        };
+
+        led.write_channels(0, 0, 0);
+        led
+    }
+
+    pub fn set_gamma(&mut self, enabled: bool) {
+        self.gamma = enabled;
+    }
+
+    // Per channel scale (255 = full) to even out LEDs where one color is much brighter than the others
+    pub fn set_white_balance(&mut self, red: u8, green: u8, blue: u8) {
+        self.white_balance = (red, green, blue);
+    }
+
+    // Sets a solid color in 0xRRGGBB form
+    pub fn set_color(&mut self, color: u32) {
+        self.set_pattern(color, LedPattern::Solid);
+    }
+
+    pub fn set_rgb(&mut self, red: u8, green: u8, blue: u8) {
+        self.set_color(color_math::rgb_to_u32(red, green, blue));
+    }
+
+    pub fn set_hsl(&mut self, hue: f32, saturation: f32, luminance: f32) {
+        self.set_color(color_math::hsl_to_rgb(hue, saturation, luminance));
+    }
+
+    pub fn set_pattern(&mut self, color: u32, pattern: LedPattern) {
+        self.color = color;
+        self.pattern = pattern;
+        self.pattern_start = Instant::now();
+        self.update();
+    }
+
+    pub fn set_status(&mut self, status: LedStatus) {
+        match status {
+            LedStatus::Off => self.set_pattern(0x000000, LedPattern::Solid),
+            LedStatus::Idle => self.set_pattern(0x0000FF, LedPattern::Breathe { period_ms: 3000 }),
+            LedStatus::Ok => self.set_pattern(0x00FF00, LedPattern::Solid),
+            LedStatus::Busy => self.set_pattern(0x0000FF, LedPattern::Blink { period_ms: 500 }),
+            LedStatus::Warning => self.set_pattern(0xFF6000, LedPattern::Breathe { period_ms: 1500 }),
+            LedStatus::Error => self.set_pattern(0xFF0000, LedPattern::Blink { period_ms: 250 }),
+        }
+    }
+
+    pub fn off(&mut self) {
+        self.set_status(LedStatus::Off);
+    }
+
+    // Call this from the main loop so blink and breathe patterns advance
+    pub fn update(&mut self) {
+        let (red, green, blue) = color_math::u32_to_rgb(self.color);
+        let level = self.pattern_level();
+
+        self.write_channels(
+            color_math::scale8(red, level),
+            color_math::scale8(green, level),
+            color_math::scale8(blue, level)
+        );
+    }
+
+    // Brightness of the pattern right now from 0 to 255
+    fn pattern_level(&self) -> u8 {
+        let elapsed = self.pattern_start.elapsed().as_millis();
+
+        match self.pattern {
+            LedPattern::Solid => 255,
+            LedPattern::Blink { period_ms } => {
+                if period_ms == 0 || elapsed % period_ms < period_ms / 2 {
+                    255
+                } else {
+                    0
+                }
+            },
+            LedPattern::Breathe { period_ms } => {
+                let half = period_ms / 2;
+                if half == 0 {
+                    return 255;
+                }
+
+                // Triangle wave, gamma correction makes it look like a smooth fade
+                let phase = elapsed % (half * 2);
+                let ramp = if phase < half { phase } else { (half * 2) - phase };
+                ((ramp * 255) / half) as u8
+            },
+        }
+    }
+
+    fn write_channels(&self, red: u8, green: u8, blue: u8) {
+        let channels = [
+            (self.red_pin, red, self.white_balance.0),
+            (self.green_pin, green, self.white_balance.1),
+            (self.blue_pin, blue, self.white_balance.2),
+        ];
+
+        for (pin, value, balance) in channels {
+            let mut value = color_math::scale8(value, balance);
+            if self.gamma {
+                value = color_math::gamma_correct(value);
+            }
+
+            let percent = match self.polarity {
+                LedPolarity::CommonAnode => math::map32(value as f32, 0.0, 255.0, 1.0, 0.0),
+                LedPolarity::CommonCathode => math::map32(value as f32, 0.0, 255.0, 0.0, 1.0),
+            };
+
+            self.pwm_driver.set_pwm_value_percent(pin, percent);
+        }
+    }
+}
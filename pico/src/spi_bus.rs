@@ -0,0 +1,248 @@
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_time::{block_for, Duration, Timer};
+use embedded_hal::spi::{ErrorType, Operation};
+
+use crate::gpio::{CtrlStatus, GPIODriver};
+use crate::spi::{SPIDriver, SPIError, SpiConfig, SpiInstance};
+
+// Words are copied through a stack buffer this big since the driver works in u16
+const CHUNK_SIZE: usize = 32;
+
+// Word sizes the bus devices can be used with
+pub trait SpiWord: Copy + 'static {
+    fn to_u16(self) -> u16;
+    fn from_u16(word: u16) -> Self;
+}
+
+impl SpiWord for u8 {
+    fn to_u16(self) -> u16 {
+        self as u16
+    }
+
+    fn from_u16(word: u16) -> Self {
+        word as u8
+    }
+}
+
+impl SpiWord for u16 {
+    fn to_u16(self) -> u16 {
+        self
+    }
+
+    fn from_u16(word: u16) -> Self {
+        word
+    }
+}
+
+// One SPI instance shared between several devices, each with its own chip select
+pub struct SharedSpiBus<'a, T: SpiInstance> {
+    spi_driver: &'a SPIDriver<T>,
+    lock: Mutex<CriticalSectionRawMutex, ()>,
+}
+
+impl<'a, T: SpiInstance> SharedSpiBus<'a, T> {
+    pub fn new(spi_driver: &'a SPIDriver<T>) -> Self {
+        SharedSpiBus {
+            spi_driver,
+            lock: Mutex::new(()),
+        }
+    }
+
+    // The chip select pin is driven high (deselected) straight away
+    pub fn device(&'a self, chip_select: usize, config: SpiConfig) -> SpiBusDevice<'a, T> {
+        let gpio_driver = GPIODriver::begin();
+        gpio_driver.set_pin(chip_select, CtrlStatus::High);
+
+        SpiBusDevice {
+            bus: self,
+            gpio_driver,
+            chip_select,
+            config,
+        }
+    }
+}
+
+// Deselects on drop, so a transaction that fails or gets cancelled part way (e.g. by with_timeout)
+// doesn't leave its device selected
+struct Selected<'d> {
+    gpio_driver: &'d GPIODriver,
+    chip_select: usize,
+}
+
+impl Drop for Selected<'_> {
+    fn drop(&mut self) {
+        self.gpio_driver.set_pin(self.chip_select, CtrlStatus::High);
+    }
+}
+
+// Handle for one device on a SharedSpiBus, its config gets applied at the start of every transaction
+pub struct SpiBusDevice<'a, T: SpiInstance> {
+    bus: &'a SharedSpiBus<'a, T>,
+    gpio_driver: GPIODriver,
+    chip_select: usize,
+    config: SpiConfig,
+}
+
+impl<'a, T: SpiInstance> SpiBusDevice<'a, T> {
+    pub fn set_config(&mut self, config: SpiConfig) {
+        self.config = config;
+    }
+
+    // Chip select stays low until the returned guard is dropped
+    fn select(&self) -> Selected<'_> {
+        self.gpio_driver.set_pin(self.chip_select, CtrlStatus::Low);
+
+        Selected {
+            gpio_driver: &self.gpio_driver,
+            chip_select: self.chip_select,
+        }
+    }
+
+    fn run_operation<W: SpiWord>(&self, operation: &mut Operation<'_, W>) -> Result<(), SPIError> {
+        let spi = self.bus.spi_driver;
+        let mut words = [0u16; CHUNK_SIZE];
+
+        match operation {
+            Operation::Read(read) => {
+                for chunk in read.chunks_mut(CHUNK_SIZE) {
+                    let words = &mut words[..chunk.len()];
+                    spi.read(words)?;
+                    copy_from_words(chunk, words);
+                }
+            },
+            Operation::Write(write) => {
+                for chunk in write.chunks(CHUNK_SIZE) {
+                    let words = copy_to_words(&mut words, chunk);
+                    spi.write(words)?;
+                }
+            },
+            Operation::Transfer(read, write) => {
+                let len = read.len().max(write.len());
+                let mut received = [0u16; CHUNK_SIZE];
+
+                for start in (0..len).step_by(CHUNK_SIZE) {
+                    let end = (start + CHUNK_SIZE).min(len);
+                    let words = fill_padded(&mut words, write, start, end);
+                    let received = &mut received[..end - start];
+                    spi.transfer(received, words)?;
+                    store_clipped(read, received, start);
+                }
+            },
+            Operation::TransferInPlace(buffer) => {
+                for chunk in buffer.chunks_mut(CHUNK_SIZE) {
+                    let words = copy_to_words(&mut words, chunk);
+                    spi.transfer_in_place(words)?;
+                    copy_from_words(chunk, words);
+                }
+            },
+            Operation::DelayNs(ns) => block_for(Duration::from_nanos(*ns as u64)),
+        }
+
+        Ok(())
+    }
+
+    async fn run_operation_async<W: SpiWord>(&self, operation: &mut Operation<'_, W>) -> Result<(), SPIError> {
+        let spi = self.bus.spi_driver;
+        let mut words = [0u16; CHUNK_SIZE];
+
+        match operation {
+            Operation::Read(read) => {
+                for chunk in read.chunks_mut(CHUNK_SIZE) {
+                    let words = &mut words[..chunk.len()];
+                    spi.read_irq(words).await?;
+                    copy_from_words(chunk, words);
+                }
+            },
+            Operation::Write(write) => {
+                for chunk in write.chunks(CHUNK_SIZE) {
+                    let words = copy_to_words(&mut words, chunk);
+                    spi.write_irq(words).await?;
+                }
+            },
+            Operation::Transfer(read, write) => {
+                let len = read.len().max(write.len());
+                let mut received = [0u16; CHUNK_SIZE];
+
+                for start in (0..len).step_by(CHUNK_SIZE) {
+                    let end = (start + CHUNK_SIZE).min(len);
+                    let words = fill_padded(&mut words, write, start, end);
+                    let received = &mut received[..end - start];
+                    spi.transfer_irq(received, words).await?;
+                    store_clipped(read, received, start);
+                }
+            },
+            Operation::TransferInPlace(buffer) => {
+                for chunk in buffer.chunks_mut(CHUNK_SIZE) {
+                    let words = copy_to_words(&mut words, chunk);
+                    spi.transfer_in_place_irq(words).await?;
+                    copy_from_words(chunk, words);
+                }
+            },
+            Operation::DelayNs(ns) => Timer::after(Duration::from_nanos(*ns as u64)).await,
+        }
+
+        Ok(())
+    }
+}
+
+fn copy_to_words<'b, W: SpiWord>(words: &'b mut [u16; CHUNK_SIZE], chunk: &[W]) -> &'b mut [u16] {
+    for (word, value) in words.iter_mut().zip(chunk) {
+        *word = value.to_u16();
+    }
+    &mut words[..chunk.len()]
+}
+
+fn copy_from_words<W: SpiWord>(chunk: &mut [W], words: &[u16]) {
+    for (value, word) in chunk.iter_mut().zip(words) {
+        *value = W::from_u16(*word);
+    }
+}
+
+// Words start..end of write, zeros past its end
+fn fill_padded<'b, W: SpiWord>(words: &'b mut [u16; CHUNK_SIZE], write: &[W], start: usize, end: usize) -> &'b [u16] {
+    for (offset, word) in words[..end - start].iter_mut().enumerate() {
+        *word = write.get(start + offset).map_or(0, |value| value.to_u16());
+    }
+    &words[..end - start]
+}
+
+// Stores received words at start in read, anything past its end is dropped
+fn store_clipped<W: SpiWord>(read: &mut [W], received: &[u16], start: usize) {
+    for (offset, word) in received.iter().enumerate() {
+        if let Some(value) = read.get_mut(start + offset) {
+            *value = W::from_u16(*word);
+        }
+    }
+}
+
+impl<T: SpiInstance> ErrorType for SpiBusDevice<'_, T> {
+    type Error = SPIError;
+}
+
+// The blocking version can't wait for the lock so it fails with BusBusy if another task holds it
+// Waiting here would hang for good when the holder is an async device on the same executor, since it only
+// gives the lock back once this executor gets to run it again
+impl<T: SpiInstance, W: SpiWord> embedded_hal::spi::SpiDevice<W> for SpiBusDevice<'_, T> {
+    fn transaction(&mut self, operations: &mut [Operation<'_, W>]) -> Result<(), Self::Error> {
+        let _lock = self.bus.lock.try_lock().map_err(|_| SPIError::BusBusy)?;
+
+        self.bus.spi_driver.configure(&self.config)?;
+        let _selected = self.select();
+        operations.iter_mut().try_for_each(|operation| self.run_operation(operation))
+    }
+}
+
+impl<T: SpiInstance, W: SpiWord> embedded_hal_async::spi::SpiDevice<W> for SpiBusDevice<'_, T> {
+    async fn transaction(&mut self, operations: &mut [Operation<'_, W>]) -> Result<(), Self::Error> {
+        let _lock = self.bus.lock.lock().await;
+
+        self.bus.spi_driver.configure(&self.config)?;
+        let _selected = self.select();
+        for operation in operations.iter_mut() {
+            self.run_operation_async(operation).await?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,203 @@
+// Plain settings and clock maths for the SPI driver, nothing in here touches the hardware
+// so it's part of the host tested library
+
+use embassy_time::Duration;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SPIFormat {
+    Motorola,
+    TexasInstruments,
+    Microwire,
+}
+
+// Clock polarity (CPOL / SPO) and phase (CPHA / SPH), only used by the Motorola format
+#[derive(Copy, Clone)]
+pub enum SPIMode {
+    Mode0, // Idle low, sample on the rising edge
+    Mode1, // Idle low, sample on the falling edge
+    Mode2, // Idle high, sample on the falling edge
+    Mode3, // Idle high, sample on the rising edge
+}
+
+impl SPIMode {
+    // Returns (polarity, phase)
+    pub fn bits(&self) -> (bool, bool) {
+        match self {
+            SPIMode::Mode0 => (false, false),
+            SPIMode::Mode1 => (false, true),
+            SPIMode::Mode2 => (true, false),
+            SPIMode::Mode3 => (true, true),
+        }
+    }
+}
+
+// How a slave's CSn input is driven
+#[derive(Copy, Clone)]
+pub enum SlaveSelect {
+    Pin(usize),            // Driven by the master on this pin
+    AlwaysSelected(usize), // This CSn pin is held low internally so the slave is always listening
+}
+
+#[derive(Copy, Clone)]
+pub enum SPIRole {
+    Master,
+    // output_disable (SOD) keeps TX off the bus so several slaves can listen to one broadcast
+    // Note: with the Motorola format and SPH = 0 the master has to toggle CSn between every frame
+    Slave { chip_select: SlaveSelect, output_disable: bool },
+}
+
+#[derive(Copy, Clone)]
+pub struct SpiConfig {
+    pub role: SPIRole,
+    pub mode: SPIMode,
+    pub data_bits: u8, // 4 - 16 bits per frame
+    pub format: SPIFormat,
+    pub baudrate: u32,
+    pub timeout: Option<Duration>, // None waits forever
+}
+
+impl Default for SpiConfig {
+    fn default() -> Self {
+        SpiConfig {
+            role: SPIRole::Master,
+            mode: SPIMode::Mode0,
+            data_bits: 8,
+            format: SPIFormat::Motorola,
+            baudrate: 1_000_000,
+            timeout: None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SPIError {
+    BaudRateUnreachable,
+    Timeout,
+    NoDmaChannel,
+    LengthMismatch,
+    NotSlave,
+    BusBusy, // Another device on a shared bus is in the middle of a transaction
+    WrongFormat, // The port isn't configured with the frame format this call needs
+    Overrun, // The RX FIFO was full and a received word got lost (SSPRIS.RORRIS)
+}
+
+// Clock dividers for SSPCPSR and SSPCR0.SCR
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct BaudDivider {
+    pub prescale: u8,  // CPSDVSR, even number from 2 to 254
+    pub postdiv: u8,   // SCR, the clock is divided by postdiv + 1
+    pub frequency: u32,
+}
+
+// From the rp2040 datasheet: SSPCLKOUT = clk_peri / (CPSDVSR * (1 + SCR))
+// Picks the fastest rate that is <= baudrate
+pub fn calculate_baud_divider(peripheral_frequency: u32, baudrate: u32) -> Result<BaudDivider, SPIError> {
+    let freq = peripheral_frequency as u64;
+    let baud = baudrate as u64;
+
+    // Fastest is a prescale of 2 and no post-div
+    if baud == 0 || baud > freq / 2 {
+        return Err(SPIError::BaudRateUnreachable);
+    }
+
+    // Try every prescale with the smallest post-div (1 - 256) that keeps the output <= baudrate
+    // and keep whichever one gets closest
+    let mut best: Option<(u64, u64)> = None;
+    for prescale in (2u64..=254).step_by(2) {
+        let postdiv = (freq + (prescale * baud) - 1) / (prescale * baud);
+        if postdiv > 256 {
+            continue;
+        }
+
+        let closer = match best {
+            Some((best_prescale, best_postdiv)) => prescale * postdiv < best_prescale * best_postdiv,
+            None => true,
+        };
+        if closer {
+            best = Some((prescale, postdiv));
+        }
+    }
+
+    let (prescale, postdiv) = best.ok_or(SPIError::BaudRateUnreachable)?;
+
+    Ok(BaudDivider {
+        prescale: prescale as u8,
+        postdiv: (postdiv - 1) as u8,
+        frequency: (freq / (prescale * postdiv)) as u32,
+    })
+}
+
+impl embedded_hal::spi::Error for SPIError {
+    fn kind(&self) -> embedded_hal::spi::ErrorKind {
+        match self {
+            SPIError::Overrun => embedded_hal::spi::ErrorKind::Overrun,
+            _ => embedded_hal::spi::ErrorKind::Other,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLK_PERI: u32 = 125_000_000;
+
+    // SSPCLKOUT = clk_peri / (CPSDVSR * (1 + SCR))
+    fn datasheet_frequency(peripheral_frequency: u32, divider: &BaudDivider) -> u32 {
+        peripheral_frequency / (divider.prescale as u32 * (1 + divider.postdiv as u32))
+    }
+
+    #[test]
+    fn highest_rate_is_half_clk_peri() {
+        let divider = calculate_baud_divider(CLK_PERI, 62_500_000).unwrap();
+        assert_eq!(divider, BaudDivider { prescale: 2, postdiv: 0, frequency: 62_500_000 });
+        assert_eq!(calculate_baud_divider(CLK_PERI, 62_500_001), Err(SPIError::BaudRateUnreachable));
+    }
+
+    #[test]
+    fn lowest_rate_uses_both_dividers_fully() {
+        // 125MHz / (254 * 256) = 1922.35Hz
+        let divider = calculate_baud_divider(CLK_PERI, 1923).unwrap();
+        assert_eq!(divider, BaudDivider { prescale: 254, postdiv: 255, frequency: 1922 });
+        assert_eq!(calculate_baud_divider(CLK_PERI, 1922), Err(SPIError::BaudRateUnreachable));
+    }
+
+    #[test]
+    fn zero_is_unreachable() {
+        assert_eq!(calculate_baud_divider(CLK_PERI, 0), Err(SPIError::BaudRateUnreachable));
+    }
+
+    #[test]
+    fn known_dividers_at_125mhz() {
+        // (requested, CPSDVSR, SCR, achieved)
+        let expected = [
+            (12_500_000, 2, 4, 12_500_000),
+            (4_000_000, 2, 15, 3_906_250),
+            (2_400_000, 2, 26, 2_314_814),
+            (1_000_000, 2, 62, 992_063),
+            (400_000, 2, 156, 398_089),
+            (10_000, 50, 249, 10_000),
+        ];
+
+        for (baudrate, prescale, postdiv, frequency) in expected {
+            let divider = calculate_baud_divider(CLK_PERI, baudrate).unwrap();
+            assert_eq!(divider, BaudDivider { prescale, postdiv, frequency }, "{}Hz", baudrate);
+        }
+    }
+
+    #[test]
+    fn achieved_rate_matches_datasheet_and_never_overshoots() {
+        for peripheral_frequency in [48_000_000, 125_000_000, 133_000_000] {
+            let mut baudrate = peripheral_frequency / 2;
+            while baudrate > peripheral_frequency / (254 * 256) + 1 {
+                let divider = calculate_baud_divider(peripheral_frequency, baudrate).unwrap();
+
+                assert!(divider.prescale >= 2 && divider.prescale % 2 == 0, "{:?}", divider);
+                assert_eq!(divider.frequency, datasheet_frequency(peripheral_frequency, &divider));
+                assert!(divider.frequency <= baudrate, "{}Hz gave {:?}", baudrate, divider);
+
+                baudrate = (baudrate / 10) * 9;
+            }
+        }
+    }
+}
@@ -0,0 +1,297 @@
+// Ring buffer of recent SPI transactions, only built with the spi-trace feature
+// (cargo build --features spi-trace). Without it Recorder does nothing and there is no buffer in RAM
+use core::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(feature = "spi-trace")]
+pub use recording::{clear, dump_usb, encode, len, Recorder};
+
+static TRACE_ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Copy, Clone)]
+pub enum TraceKind {
+    Blocking = 0,
+    Dma = 1,
+    Interrupt = 2,
+}
+
+// Recording only starts once this is called, does nothing without the feature
+pub fn enable(enabled: bool) {
+    TRACE_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    cfg!(feature = "spi-trace") && TRACE_ENABLED.load(Ordering::Relaxed)
+}
+
+#[cfg(feature = "spi-trace")]
+mod recording {
+    use core::cell::RefCell;
+
+    use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+    use embassy_time::Instant;
+    use embassy_usb::class::cdc_acm::CdcAcmClass;
+    use embassy_usb::driver::{Driver, EndpointError};
+    use rp2040_pac::spi0;
+
+    use super::{is_enabled, TraceKind};
+    use crate::spi::SPIError;
+
+    // Words kept per direction for each transaction, longer transfers are cut off
+    const MAX_TRACE_WORDS: usize = 16;
+    const TRACE_DEPTH: usize = 32;
+
+    // Dump format (all little endian), decoded by tools/decode_spi_trace.py
+    //  header: "SPTR", version (u8), clk_peri Hz (u32), record count (u16)
+    //  record: instance (u8), kind (u8), status (u8), SSPCPSR (u8), SSPCR0 (u16), word count (u16),
+    //          stored words (u8), start us (u64), duration us (u32), tx words (u16 * stored), rx words (u16 * stored)
+    const TRACE_MAGIC: &[u8; 4] = b"SPTR";
+    const TRACE_VERSION: u8 = 1;
+    const HEADER_SIZE: usize = 11;
+    const RECORD_HEADER_SIZE: usize = 21;
+
+    static TRACE: Mutex<CriticalSectionRawMutex, RefCell<TraceBuffer>> = Mutex::new(RefCell::new(TraceBuffer::new()));
+
+    #[derive(Copy, Clone)]
+    struct TraceRecord {
+        instance: u8,
+        kind: u8,
+        status: u8, // 0 for Ok, otherwise the SPIError + 1
+        cpsr: u8,
+        cr0: u16,
+        word_count: u16,
+        start_us: u64,
+        duration_us: u32,
+        tx: [u16; MAX_TRACE_WORDS],
+        rx: [u16; MAX_TRACE_WORDS],
+    }
+
+    impl TraceRecord {
+        const EMPTY: TraceRecord = TraceRecord {
+            instance: 0,
+            kind: 0,
+            status: 0,
+            cpsr: 0,
+            cr0: 0,
+            word_count: 0,
+            start_us: 0,
+            duration_us: 0,
+            tx: [0; MAX_TRACE_WORDS],
+            rx: [0; MAX_TRACE_WORDS],
+        };
+
+        fn stored_words(&self) -> usize {
+            (self.word_count as usize).min(MAX_TRACE_WORDS)
+        }
+
+        fn encoded_size(&self) -> usize {
+            RECORD_HEADER_SIZE + (self.stored_words() * 4)
+        }
+    }
+
+    // Ring buffer, once full the oldest record gets overwritten
+    struct TraceBuffer {
+        records: [TraceRecord; TRACE_DEPTH],
+        next: usize,
+        count: usize,
+    }
+
+    impl TraceBuffer {
+        const fn new() -> Self {
+            TraceBuffer {
+                records: [TraceRecord::EMPTY; TRACE_DEPTH],
+                next: 0,
+                count: 0,
+            }
+        }
+
+        fn push(&mut self, record: TraceRecord) {
+            self.records[self.next] = record;
+            self.next = (self.next + 1) % TRACE_DEPTH;
+            self.count = (self.count + 1).min(TRACE_DEPTH);
+        }
+
+        fn oldest_first(&self) -> impl Iterator<Item = &TraceRecord> {
+            let start = (self.next + TRACE_DEPTH - self.count) % TRACE_DEPTH;
+            (0..self.count).map(move |offset| &self.records[(start + offset) % TRACE_DEPTH])
+        }
+    }
+
+    pub fn clear() {
+        TRACE.lock(|trace| {
+            let mut trace = trace.borrow_mut();
+            trace.next = 0;
+            trace.count = 0;
+        });
+    }
+
+    pub fn len() -> usize {
+        TRACE.lock(|trace| trace.borrow().count)
+    }
+
+    // Writes the header and as many records as fit (oldest first), returns the number of bytes used
+    pub fn encode(output: &mut [u8]) -> usize {
+        if output.len() < HEADER_SIZE {
+            return 0;
+        }
+
+        TRACE.lock(|trace| {
+            let trace = trace.borrow();
+            let mut used = HEADER_SIZE;
+            let mut written: u16 = 0;
+
+            for record in trace.oldest_first() {
+                if used + record.encoded_size() > output.len() {
+                    break;
+                }
+                used += encode_record(record, &mut output[used..]);
+                written += 1;
+            }
+
+            output[0..4].copy_from_slice(TRACE_MAGIC);
+            output[4] = TRACE_VERSION;
+            output[5..9].copy_from_slice(&embassy_rp::clocks::clk_peri_freq().to_le_bytes());
+            output[9..11].copy_from_slice(&written.to_le_bytes());
+
+            used
+        })
+    }
+
+    fn encode_record(record: &TraceRecord, output: &mut [u8]) -> usize {
+        let stored = record.stored_words();
+
+        output[0] = record.instance;
+        output[1] = record.kind;
+        output[2] = record.status;
+        output[3] = record.cpsr;
+        output[4..6].copy_from_slice(&record.cr0.to_le_bytes());
+        output[6..8].copy_from_slice(&record.word_count.to_le_bytes());
+        output[8] = stored as u8;
+        output[9..17].copy_from_slice(&record.start_us.to_le_bytes());
+        output[17..21].copy_from_slice(&record.duration_us.to_le_bytes());
+
+        let mut index = RECORD_HEADER_SIZE;
+        for word in record.tx[..stored].iter().chain(record.rx[..stored].iter()) {
+            output[index..index + 2].copy_from_slice(&word.to_le_bytes());
+            index += 2;
+        }
+
+        index
+    }
+
+    // Sends the whole trace down a CDC ACM serial port
+    pub async fn dump_usb<'d, D: Driver<'d>>(class: &mut CdcAcmClass<'d, D>) -> Result<(), EndpointError> {
+        let mut buffer = [0u8; HEADER_SIZE + TRACE_DEPTH * (RECORD_HEADER_SIZE + MAX_TRACE_WORDS * 4)];
+        let used = encode(&mut buffer);
+        let packet_size = class.max_packet_size() as usize;
+
+        for packet in buffer[..used].chunks(packet_size) {
+            class.write_packet(packet).await?;
+        }
+
+        // A full sized last packet needs a zero length packet to end the transfer
+        if used % packet_size == 0 {
+            class.write_packet(&[]).await?;
+        }
+
+        Ok(())
+    }
+
+    // Collects one transaction, does nothing when tracing is off
+    pub struct Recorder {
+        enabled: bool,
+        start: Instant,
+        record: TraceRecord,
+    }
+
+    impl Recorder {
+        pub fn start(instance: usize, spi: &spi0::RegisterBlock, kind: TraceKind, len: usize) -> Self {
+            let mut record = TraceRecord::EMPTY;
+            let enabled = is_enabled();
+
+            if enabled {
+                record.instance = instance as u8;
+                record.kind = kind as u8;
+                record.cr0 = spi.sspcr0().read().bits() as u16;
+                record.cpsr = spi.sspcpsr().read().bits() as u8;
+                record.word_count = len.min(u16::MAX as usize) as u16;
+            }
+
+            Recorder {
+                enabled,
+                start: Instant::now(),
+                record,
+            }
+        }
+
+        // Separate borrows so the TX and RX closures can both record
+        pub fn buffers_mut(&mut self) -> (&mut [u16], &mut [u16]) {
+            (&mut self.record.tx, &mut self.record.rx)
+        }
+
+        // Safety: pointer has to be valid for len words (or one word when it doesn't increment),
+        // bytes means the buffer holds one u8 per frame instead of a u16
+        pub unsafe fn capture_tx(&mut self, pointer: *const u16, increment: bool, bytes: bool, len: usize) {
+            if self.enabled {
+                copy_words(&mut self.record.tx, pointer, increment, bytes, len);
+            }
+        }
+
+        // Safety: same as capture_tx
+        pub unsafe fn capture_rx(&mut self, pointer: *const u16, increment: bool, bytes: bool, len: usize) {
+            if self.enabled {
+                copy_words(&mut self.record.rx, pointer, increment, bytes, len);
+            }
+        }
+
+        pub fn finish(mut self, result: &Result<(), SPIError>) {
+            if !self.enabled {
+                return;
+            }
+
+            self.record.status = match result {
+                Ok(()) => 0,
+                Err(error) => *error as u8 + 1,
+            };
+            self.record.start_us = self.start.as_micros();
+            self.record.duration_us = self.start.elapsed().as_micros().min(u32::MAX as u64) as u32;
+
+            TRACE.lock(|trace| trace.borrow_mut().push(self.record));
+        }
+    }
+
+    unsafe fn copy_words(words: &mut [u16; MAX_TRACE_WORDS], pointer: *const u16, increment: bool, bytes: bool, len: usize) {
+        if pointer.is_null() {
+            return;
+        }
+
+        for (index, word) in words.iter_mut().take(len).enumerate() {
+            let offset = if increment { index } else { 0 };
+            *word = if bytes {
+                (pointer as *const u8).add(offset).read_volatile() as u16
+            } else {
+                pointer.add(offset).read_volatile()
+            };
+        }
+    }
+}
+
+// Stand-in with the same interface when tracing isn't built in
+#[cfg(not(feature = "spi-trace"))]
+pub struct Recorder;
+
+#[cfg(not(feature = "spi-trace"))]
+impl Recorder {
+    pub fn start(_instance: usize, _spi: &rp2040_pac::spi0::RegisterBlock, _kind: TraceKind, _len: usize) -> Self {
+        Recorder
+    }
+
+    pub fn buffers_mut(&mut self) -> (&mut [u16], &mut [u16]) {
+        (&mut [], &mut [])
+    }
+
+    pub unsafe fn capture_tx(&mut self, _pointer: *const u16, _increment: bool, _bytes: bool, _len: usize) {}
+
+    pub unsafe fn capture_rx(&mut self, _pointer: *const u16, _increment: bool, _bytes: bool, _len: usize) {}
+
+    pub fn finish(self, _result: &Result<(), crate::spi::SPIError>) {}
+}
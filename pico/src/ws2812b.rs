@@ -1,68 +1,244 @@
-use embassy_rp::spi;
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::led_power::{self, PowerModel};
+use crate::pixel_format::encode_pixel;
+use crate::spi::{SPIDriver, SPIError, SpiInstance};
+use crate::ws2812b_timing::{self, LedChip, SpiLedTiming};
+
+pub use crate::pixel_format::PixelFormat;
 
-const DRIVER_FREQUENCY: u32 = 8_500_000;
 pub const INFO_SIZE: usize = 24;
-const LOGIC_0: u16 = 0xE0;
-const LOGIC_1: u16 = 0xFC;
-pub const NUM_LEDS: usize = 12;
-pub const LED_INFO_SIZE: usize = 2 + (INFO_SIZE * NUM_LEDS);
+const CHANNEL_SIZE: usize = 8; // One word per bit
+// Words of low output sent before the LED data
+const RESET_SIZE: usize = 2;
+// Line has to stay low at least this long between frames so the LEDs latch
+pub const RESET_TIME: Duration = Duration::from_micros(300);
+
+// Number of words generate_addressable_led_buffer needs for a strip of leds
+pub const fn buffer_size(leds: usize) -> usize {
+    RESET_SIZE + (INFO_SIZE * leds)
+}
+
+// Count must be buffer_size(number of leds), the timing comes from ws2812b_timing::calculate_spi_timing
+pub fn generate_addressable_led_buffer<const COUNT: usize>(color_values: &[u32], timing: &SpiLedTiming) -> [u16; COUNT] {
+    let mut addressable_led_buffer: [u16; COUNT] = [0; COUNT];
+
+    let mut index = RESET_SIZE;
+    for color in color_values.iter() {
+        index += encode_pixel(*color, PixelFormat::Grb, timing, &mut addressable_led_buffer[index..]);
+    }
+    addressable_led_buffer
+}
+
+// Anything that can put a frame of 0xWWRRGGBB pixels onto a strip, LedStrip works the same on top of any of them
+pub trait LedDriver<const LEDS: usize> {
+    type Error;
+
+    async fn write(&mut self, pixels: &[u32; LEDS]) -> Result<(), Self::Error>;
+}
+
+// A strip of LEDS addressable LEDs, colors are 0xRRGGBB (0xWWRRGGBB for RGBW strips)
+// Brightness and the power budget are applied when the frame is sent so the pixels keep their full colors
+pub struct LedStrip<D: LedDriver<LEDS>, const LEDS: usize> {
+    driver: D,
+    pixels: [u32; LEDS],
+    output: [u32; LEDS],
+    brightness: u8,
+    power: Option<(PowerModel, u32)>,
+    requested_current: u32,
+    current: u32,
+}
+
+impl<D: LedDriver<LEDS>, const LEDS: usize> LedStrip<D, LEDS> {
+    pub fn new(driver: D) -> Self {
+        LedStrip {
+            driver,
+            pixels: [0; LEDS],
+            output: [0; LEDS],
+            brightness: 255,
+            power: None,
+            requested_current: 0,
+            current: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        LEDS
+    }
+
+    // Pixels past the end of the strip are ignored
+    pub fn set_pixel(&mut self, index: usize, color: u32) {
+        if let Some(pixel) = self.pixels.get_mut(index) {
+            *pixel = color;
+        }
+    }
+
+    pub fn pixel(&self, index: usize) -> Option<u32> {
+        self.pixels.get(index).copied()
+    }
+
+    pub fn fill(&mut self, color: u32) {
+        self.pixels = [color; LEDS];
+    }
+
+    pub fn clear(&mut self) {
+        self.fill(0);
+    }
+
+    pub fn pixels(&self) -> &[u32; LEDS] {
+        &self.pixels
+    }
+
+    pub fn pixels_mut(&mut self) -> &mut [u32; LEDS] {
+        &mut self.pixels
+    }
+
+    pub fn driver(&mut self) -> &mut D {
+        &mut self.driver
+    }
+
+    // Global cap on every channel (255 = full)
+    pub fn set_brightness(&mut self, brightness: u8) {
+        self.brightness = brightness;
+    }
 
+    pub fn brightness(&self) -> u8 {
+        self.brightness
+    }
+
+    // Frames that would draw more than budget mA get dimmed until they fit
+    pub fn set_power_budget(&mut self, model: PowerModel, budget: u32) {
+        self.power = Some((model, budget));
+    }
+
+    pub fn clear_power_budget(&mut self) {
+        self.power = None;
+    }
 
-pub fn get_addressable_led_config() -> spi::Config {
-    let mut config = spi::Config::default();
-    config.frequency = DRIVER_FREQUENCY;
-    config
+    // Estimated draw of the last frame sent in mA, 0 without a power budget
+    pub fn current(&self) -> u32 {
+        self.current
+    }
+
+    // What the last frame would have drawn without the power budget
+    pub fn requested_current(&self) -> u32 {
+        self.requested_current
+    }
+
+    // Sends the pixels out, nothing changes on the strip until this is called
+    pub async fn show(&mut self) -> Result<(), D::Error> {
+        for (output, pixel) in self.output.iter_mut().zip(self.pixels.iter()) {
+            *output = led_power::scale_pixel(*pixel, self.brightness);
+        }
+
+        if let Some((model, budget)) = self.power {
+            self.requested_current = led_power::estimate_current(&self.output, &model);
+
+            let scale = led_power::budget_scale(self.requested_current, model.idle * LEDS as u32, budget);
+            if scale < 255 {
+                for output in self.output.iter_mut() {
+                    *output = led_power::scale_pixel(*output, scale);
+                }
+            }
+
+            self.current = led_power::estimate_current(&self.output, &model);
+        }
+
+        self.driver.write(&self.output).await
+    }
+}
+
+// The encoded strip, kept as one block so it goes out in a single write
+// Sized for CHANNELS per pixel, pixels with fewer channels are packed together and leave the end unused
+#[repr(C)]
+struct Frame<const LEDS: usize, const CHANNELS: usize> {
+    reset: [u16; RESET_SIZE],
+    leds: [[[u16; CHANNEL_SIZE]; CHANNELS]; LEDS],
 }
 
-fn rgb_to_logic_buffer(color_value: u32) -> [u16; INFO_SIZE] {
-    // Get the red logic
-    let mut mask: u32 = 0x00800000;
-    let mut logic_buffer_bits: [u16; INFO_SIZE] = [0; INFO_SIZE];
+impl<const LEDS: usize, const CHANNELS: usize> Frame<LEDS, CHANNELS> {
+    const WORDS: usize = RESET_SIZE + (CHANNEL_SIZE * CHANNELS * LEDS);
 
-    for i in 0..8 {
-        if color_value & mask > 0 {
-            logic_buffer_bits[(1 * 8) + i] = LOGIC_1;
-        } else {
-            logic_buffer_bits[(1 * 8) + i] = LOGIC_0;
+    fn as_words(&self) -> &[u16] {
+        // Only u16 fields so there is no padding
+        unsafe {
+            core::slice::from_raw_parts(self as *const Self as *const u16, Self::WORDS)
         }
-        mask >>= 1;
-    }
-    
-    // Get the green logic
-    for i in 0..8 {
-        if color_value & mask > 0 {
-            logic_buffer_bits[i] = LOGIC_1;
-        } else {
-            logic_buffer_bits[i] = LOGIC_0;
+    }
+
+    fn as_words_mut(&mut self) -> &mut [u16] {
+        unsafe {
+            core::slice::from_raw_parts_mut(self as *mut Self as *mut u16, Self::WORDS)
         }
-        mask >>= 1;
     }
+}
+
+// Sends every LED bit as one SPI frame, the frame size, clock and bit patterns are worked out for the chip
+// from clk_peri. The SPI gets configured here, it only needs DMA channels set
+// The frame has room for CHANNELS per pixel, set it to 4 for RGBW strips
+pub struct SpiLedDriver<'a, T: SpiInstance, const LEDS: usize, const CHANNELS: usize = 3> {
+    spi: &'a SPIDriver<T>,
+    format: PixelFormat,
+    timing: SpiLedTiming,
+    frame: Frame<LEDS, CHANNELS>,
+    ready_at: Instant,
+}
+
+impl<'a, T: SpiInstance, const LEDS: usize, const CHANNELS: usize> SpiLedDriver<'a, T, LEDS, CHANNELS> {
+    // Fails with LengthMismatch if the format has more channels than the frame has room for
+    pub fn new(spi: &'a SPIDriver<T>, chip: LedChip, format: PixelFormat) -> Result<Self, SPIError> {
+        if format.channels() > CHANNELS {
+            return Err(SPIError::LengthMismatch);
+        }
 
-    // Get the blue logic
-    for i in 0..8 {
-        if color_value & mask > 0 {
-            logic_buffer_bits[(2 * 8) + i] = LOGIC_1;
-        } else {
-            logic_buffer_bits[(2 * 8) + i] = LOGIC_0;
+        let timing = ws2812b_timing::calculate_spi_timing(embassy_rp::clocks::clk_peri_freq(), &chip.timing())?;
+        if !timing.within_tolerance() {
+            log::warn!("{:?} timing is off by {}ns, more than the datasheet allows", chip, timing.max_error);
         }
-        mask >>= 1;
+
+        spi.configure(&timing.spi_config())?;
+
+        Ok(SpiLedDriver {
+            spi,
+            format,
+            timing,
+            frame: Frame {
+                reset: [0; RESET_SIZE],
+                leds: [[[0; CHANNEL_SIZE]; CHANNELS]; LEDS],
+            },
+            ready_at: Instant::now(),
+        })
+    }
+
+    pub fn timing(&self) -> &SpiLedTiming {
+        &self.timing
     }
 
-    logic_buffer_bits
+    pub fn set_pixel_format(&mut self, format: PixelFormat) -> Result<(), SPIError> {
+        if format.channels() > CHANNELS {
+            return Err(SPIError::LengthMismatch);
+        }
+
+        self.format = format;
+        Ok(())
+    }
 }
 
-// Count must be the number of leds * INFO_SIZE
-pub fn generate_addressable_led_buffer<const COUNT: usize>(color_values: &[u32]) -> [u16; COUNT] {
-    let mut addressable_led_buffer: [u16; COUNT] = [0; COUNT];
+impl<'a, T: SpiInstance, const LEDS: usize, const CHANNELS: usize> LedDriver<LEDS> for SpiLedDriver<'a, T, LEDS, CHANNELS> {
+    type Error = SPIError;
 
-    let mut index: u32 = 2;
-    for color in color_values.iter() {
-        let color_buffer = rgb_to_logic_buffer(*color);
+    async fn write(&mut self, pixels: &[u32; LEDS]) -> Result<(), SPIError> {
+        let words = self.frame.as_words_mut();
+        let mut used = RESET_SIZE;
 
-        for i in 0..INFO_SIZE {
-            addressable_led_buffer[index as usize] = color_buffer[i];
-            index += 1;
+        for color in pixels.iter() {
+            used += encode_pixel(*color, self.format, &self.timing, &mut words[used..]);
         }
+
+        Timer::at(self.ready_at).await;
+        let result = self.spi.write_async(&self.frame.as_words()[..used]).await;
+        self.ready_at = Instant::now() + self.timing.reset;
+
+        result
     }
-    addressable_led_buffer
-}
\ No newline at end of file
+}
@@ -0,0 +1,99 @@
+use embassy_time::{Instant, Timer};
+
+use crate::pixel_format::frame_bytes_mut;
+use crate::spi::{SPIDriver, SPIError, SpiInstance};
+use crate::ws2812b::{LedDriver, PixelFormat, RESET_TIME};
+
+pub use crate::compact_encoding::{BitEncoding, CompactEncoder};
+
+// Encodes the whole frame into a byte buffer and sends it by DMA, 9 bytes per RGB pixel with ThreeBit
+// instead of the 24 words (48 bytes) SpiLedDriver uses
+// PIXEL_BYTES has to fit encoding.pixel_bytes(format): 9 or 12 for RGB, 12 or 16 for RGBW
+pub struct CompactSpiLedDriver<'a, T: SpiInstance, const LEDS: usize, const PIXEL_BYTES: usize = 9> {
+    spi: &'a SPIDriver<T>,
+    format: PixelFormat,
+    encoding: BitEncoding,
+    frame: [[u8; PIXEL_BYTES]; LEDS],
+    ready_at: Instant,
+}
+
+impl<'a, T: SpiInstance, const LEDS: usize, const PIXEL_BYTES: usize> CompactSpiLedDriver<'a, T, LEDS, PIXEL_BYTES> {
+    // Configures the SPI for the encoding, it needs DMA channels set already
+    // Fails with LengthMismatch if an encoded pixel doesn't fit in PIXEL_BYTES, or BaudRateUnreachable if
+    // clk_peri can't be divided down to a clock that keeps the encoding within the WS2812B tolerance
+    pub fn new(spi: &'a SPIDriver<T>, format: PixelFormat, encoding: BitEncoding) -> Result<Self, SPIError> {
+        if encoding.pixel_bytes(format) > PIXEL_BYTES {
+            return Err(SPIError::LengthMismatch);
+        }
+
+        let timing = encoding.timing(embassy_rp::clocks::clk_peri_freq())?;
+        spi.configure(&encoding.spi_config(&timing))?;
+
+        Ok(CompactSpiLedDriver {
+            spi,
+            format,
+            encoding,
+            frame: [[0; PIXEL_BYTES]; LEDS],
+            ready_at: Instant::now(),
+        })
+    }
+
+}
+
+impl<'a, T: SpiInstance, const LEDS: usize, const PIXEL_BYTES: usize> LedDriver<LEDS> for CompactSpiLedDriver<'a, T, LEDS, PIXEL_BYTES> {
+    type Error = SPIError;
+
+    async fn write(&mut self, pixels: &[u32; LEDS]) -> Result<(), SPIError> {
+        let encoder = CompactEncoder::new(pixels, self.format, self.encoding);
+        let used = encoder.len();
+        let bytes = frame_bytes_mut(&mut self.frame);
+
+        for (byte, encoded) in bytes.iter_mut().zip(encoder) {
+            *byte = encoded;
+        }
+
+        Timer::at(self.ready_at).await;
+        let result = self.spi.write_bytes_async(&bytes[..used]).await;
+        self.ready_at = Instant::now() + RESET_TIME;
+
+        result
+    }
+}
+
+// No frame buffer at all, bytes are encoded straight into the TX FIFO
+// Blocks for the whole frame, an interrupt longer than ~20us in the middle can latch the strip early
+pub struct StreamingSpiLedDriver<'a, T: SpiInstance> {
+    spi: &'a SPIDriver<T>,
+    format: PixelFormat,
+    encoding: BitEncoding,
+    ready_at: Instant,
+}
+
+impl<'a, T: SpiInstance> StreamingSpiLedDriver<'a, T> {
+    // Same checks as CompactSpiLedDriver::new except for the frame size
+    pub fn new(spi: &'a SPIDriver<T>, format: PixelFormat, encoding: BitEncoding) -> Result<Self, SPIError> {
+        let timing = encoding.timing(embassy_rp::clocks::clk_peri_freq())?;
+        spi.configure(&encoding.spi_config(&timing))?;
+
+        Ok(StreamingSpiLedDriver {
+            spi,
+            format,
+            encoding,
+            ready_at: Instant::now(),
+        })
+    }
+}
+
+impl<'a, T: SpiInstance, const LEDS: usize> LedDriver<LEDS> for StreamingSpiLedDriver<'a, T> {
+    type Error = SPIError;
+
+    async fn write(&mut self, pixels: &[u32; LEDS]) -> Result<(), SPIError> {
+        Timer::at(self.ready_at).await;
+
+        let encoder = CompactEncoder::new(pixels, self.format, self.encoding);
+        let result = self.spi.write_iter(encoder.map(|byte| byte as u16));
+        self.ready_at = Instant::now() + RESET_TIME;
+
+        result
+    }
+}
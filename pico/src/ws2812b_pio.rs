@@ -0,0 +1,192 @@
+use embassy_futures::yield_now;
+use embassy_rp::interrupt::typelevel::{Binding, DMA_IRQ_1};
+use embassy_time::{Instant, Timer};
+use pio::{Instruction, InstructionOperands, JmpCondition, SetDestination};
+use rp2040_pac::{pio0, PIO0, PIO1};
+
+use crate::dma::{self, DMADriver, Transfer, TransferSize};
+use crate::gpio::{CtrlStatus, GPIODriver};
+use crate::ws2812b::{LedDriver, PixelFormat, RESET_TIME};
+
+const BIT_FREQUENCY: u32 = 800_000;
+// Cycles per part of a bit, every bit is T1 + T2 + T3 cycles
+//  0: high for T1, low for T2 + T3 (375ns / 875ns)
+//  1: high for T1 + T2, low for T3 (750ns / 500ns)
+const T1: u32 = 3;
+const T2: u32 = 3;
+const T3: u32 = 4;
+const CYCLES_PER_BIT: u32 = T1 + T2 + T3;
+
+#[derive(Copy, Clone)]
+pub enum PioSelector {
+    Pio0,
+    Pio1,
+}
+
+// Same pixel API as the SPI driver but the waveform comes out of a PIO state machine,
+// so the timing is exact and doesn't depend on the SPI clock
+// The program is loaded at the start of the instruction memory so it can't share a PIO with other programs
+pub struct PioLedDriver<const LEDS: usize> {
+    pio: &'static pio0::RegisterBlock,
+    state_machine: usize,
+    dreq: u8,
+    dma: DMADriver,
+    dma_channel: usize,
+    format: PixelFormat,
+    words: [u32; LEDS],
+    ready_at: Instant,
+}
+
+impl<const LEDS: usize> PioLedDriver<LEDS> {
+    // Irqs needs DMA_IRQ_1 bound to dma::InterruptHandler
+    pub fn new(
+        pio_selector: PioSelector,
+        state_machine: usize,
+        pin: usize,
+        dma_channel: usize,
+        format: PixelFormat,
+        irqs: impl Binding<DMA_IRQ_1, dma::InterruptHandler>
+    ) -> Self {
+        let gpio_driver = GPIODriver::begin();
+        let (pio, dreq) = match pio_selector {
+            PioSelector::Pio0 => {
+                gpio_driver.set_pin(pin, CtrlStatus::Pio0);
+                (unsafe { &*PIO0::ptr() }, dma::dreq::PIO0_TX0 + state_machine as u8)
+            },
+            PioSelector::Pio1 => {
+                gpio_driver.set_pin(pin, CtrlStatus::Pio1);
+                (unsafe { &*PIO1::ptr() }, dma::dreq::PIO1_TX0 + state_machine as u8)
+            },
+        };
+
+        let driver = PioLedDriver {
+            pio,
+            state_machine,
+            dreq,
+            dma: DMADriver::begin(irqs),
+            dma_channel,
+            format,
+            words: [0; LEDS],
+            ready_at: Instant::now(),
+        };
+
+        driver.load(pin);
+        driver
+    }
+
+    fn load(&self, pin: usize) {
+        // The delays are T3 - 1, T1 - 1 and T2 - 1 since every instruction already takes a cycle
+        let program = pio_proc::pio_asm!(
+            ".side_set 1",
+            ".wrap_target",
+            "bitloop:",
+            "    out x, 1       side 0 [3]",
+            "    jmp !x do_zero side 1 [2]",
+            "    jmp bitloop    side 1 [2]",
+            "do_zero:",
+            "    nop            side 0 [2]",
+            ".wrap",
+        ).program;
+
+        let sm_mask = 1u8 << self.state_machine;
+        let sm = self.pio.sm(self.state_machine);
+
+        self.pio.ctrl().modify(|r, w| unsafe { w.sm_enable().bits(r.sm_enable().bits() & !sm_mask) });
+
+        for (index, instruction) in program.code.iter().enumerate() {
+            self.pio.instr_mem(index).write(|w| unsafe { w.bits(*instruction as u32) });
+        }
+
+        // 24.8 fixed point divider from clk_sys
+        let divider = ((embassy_rp::clocks::clk_sys_freq() as u64) << 8) / (BIT_FREQUENCY * CYCLES_PER_BIT) as u64;
+        sm.sm_clkdiv().write(|w| unsafe {
+            w.int().bits((divider >> 8) as u16);
+            w.frac().bits(divider as u8)
+        });
+
+        sm.sm_execctrl().write(|w| unsafe {
+            w.wrap_bottom().bits(program.wrap.target);
+            w.wrap_top().bits(program.wrap.source);
+            w.side_en().bit(program.side_set.optional())
+        });
+
+        // Pixels are pulled 24 or 32 bits at a time MSB first (a threshold of 0 means 32),
+        // joining the FIFOs gives the DMA more room
+        sm.sm_shiftctrl().write(|w| unsafe {
+            w.autopull().set_bit();
+            w.pull_thresh().bits(((self.format.channels() * 8) % 32) as u8);
+            w.out_shiftdir().clear_bit();
+            w.fjoin_tx().set_bit()
+        });
+
+        sm.sm_pinctrl().write(|w| unsafe {
+            w.sideset_base().bits(pin as u8);
+            w.sideset_count().bits(program.side_set.bits());
+            w.set_base().bits(pin as u8);
+            w.set_count().bits(1)
+        });
+
+        // Make the pin an output and start from the top of the program
+        let startup = [
+            InstructionOperands::SET { destination: SetDestination::PINDIRS, data: 1 },
+            InstructionOperands::JMP { condition: JmpCondition::Always, address: program.wrap.target },
+        ];
+        for operands in startup {
+            let instruction = Instruction { operands, delay: 0, side_set: Some(0) };
+            sm.sm_instr().write(|w| unsafe { w.bits(instruction.encode(program.side_set) as u32) });
+        }
+
+        self.pio.ctrl().modify(|r, w| unsafe {
+            w.sm_restart().bits(sm_mask);
+            w.clkdiv_restart().bits(sm_mask);
+            w.sm_enable().bits(r.sm_enable().bits() | sm_mask)
+        });
+    }
+
+    // Set once the state machine ran out of bits, the line stays low from then on
+    fn is_stalled(&self) -> bool {
+        self.pio.fdebug().read().txstall().bits() & (1 << self.state_machine) != 0
+    }
+
+    fn clear_stall(&self) {
+        self.pio.fdebug().write(|w| unsafe { w.txstall().bits(1 << self.state_machine) });
+    }
+}
+
+impl<const LEDS: usize> LedDriver<LEDS> for PioLedDriver<LEDS> {
+    type Error = core::convert::Infallible;
+
+    async fn write(&mut self, pixels: &[u32; LEDS]) -> Result<(), Self::Error> {
+        // Wire order from the top byte down since the state machine shifts out MSB first
+        for (word, color) in self.words.iter_mut().zip(pixels.iter()) {
+            *word = u32::from_be_bytes(self.format.wire_bytes(*color));
+        }
+
+        // The previous frame has to finish latching first
+        Timer::at(self.ready_at).await;
+
+        unsafe {
+            self.dma.start(self.dma_channel, &Transfer {
+                read_address: self.words.as_ptr() as u32,
+                write_address: self.pio.txf(self.state_machine).as_ptr() as u32,
+                count: LEDS as u32,
+                size: TransferSize::Word,
+                increment_read: true,
+                increment_write: false,
+                dreq: self.dreq,
+            });
+        }
+        self.dma.wait(self.dma_channel).await;
+
+        // The FIFO still holds the last few pixels once the DMA is done. The flag is still set from the
+        // stall at the end of the previous frame (and again while waiting for the first word), so clear it
+        // now and wait for it to come back once the FIFO really runs dry
+        self.clear_stall();
+        while !self.is_stalled() {
+            yield_now().await;
+        }
+        self.ready_at = Instant::now() + RESET_TIME;
+
+        Ok(())
+    }
+}
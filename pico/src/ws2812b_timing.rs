@@ -0,0 +1,212 @@
+use embassy_time::Duration;
+
+use crate::spi_config::{calculate_baud_divider, SPIError, SPIFormat, SPIMode, SPIRole, SpiConfig};
+
+// Frame sizes the SSP supports, each LED bit is sent as one frame
+const MIN_FRAME_BITS: u8 = 4;
+const MAX_FRAME_BITS: u8 = 16;
+const PS_PER_SECOND: u64 = 1_000_000_000_000;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum LedChip {
+    Ws2812b,
+    Ws2811, // 800kHz mode
+    Sk6812,
+}
+
+// Datasheet timings, all in ns except the reset
+#[derive(Copy, Clone, Debug)]
+pub struct LedTiming {
+    pub t0h: u32,
+    pub t1h: u32,
+    pub t0l: u32,
+    pub t1l: u32,
+    pub tolerance: u32, // Every high and low time can be off by this much
+    pub reset_us: u32,
+}
+
+impl LedChip {
+    pub fn timing(&self) -> LedTiming {
+        match self {
+            LedChip::Ws2812b => LedTiming { t0h: 400, t1h: 800, t0l: 850, t1l: 450, tolerance: 150, reset_us: 280 },
+            LedChip::Ws2811 => LedTiming { t0h: 250, t1h: 600, t0l: 1000, t1l: 650, tolerance: 150, reset_us: 280 },
+            LedChip::Sk6812 => LedTiming { t0h: 300, t1h: 600, t0l: 900, t1l: 600, tolerance: 150, reset_us: 80 },
+        }
+    }
+}
+
+// What the SPI has to be set to and the timing that actually comes out of it (ns)
+#[derive(Copy, Clone, Debug)]
+pub struct SpiLedTiming {
+    pub baudrate: u32,
+    pub frame_bits: u8,
+    pub logic_0: u16,
+    pub logic_1: u16,
+    pub t0h: u32,
+    pub t1h: u32,
+    pub t0l: u32,
+    pub t1l: u32,
+    pub max_error: u32, // Worst difference from the datasheet over all four times
+    pub tolerance: u32,
+    pub reset: Duration,
+}
+
+impl SpiLedTiming {
+    pub fn within_tolerance(&self) -> bool {
+        self.max_error <= self.tolerance
+    }
+
+    // SPH = 1 so the frames go out back to back without a gap for CSn
+    pub fn spi_config(&self) -> SpiConfig {
+        SpiConfig {
+            role: SPIRole::Master,
+            mode: SPIMode::Mode1,
+            data_bits: self.frame_bits,
+            format: SPIFormat::Motorola,
+            baudrate: self.baudrate,
+            timeout: Some(Duration::from_millis(100)),
+        }
+    }
+}
+
+// Tries every frame size with the dividers closest to the LED bit period and keeps the one with the smallest error
+// Only fails if no divider works at all, check within_tolerance() for whether the LEDs will accept it
+pub fn calculate_spi_timing(peripheral_frequency: u32, timing: &LedTiming) -> Result<SpiLedTiming, SPIError> {
+    let period_ps = (timing.t0h + timing.t0l) as u64 * 1000;
+    let mut best: Option<SpiLedTiming> = None;
+
+    for frame_bits in MIN_FRAME_BITS..=MAX_FRAME_BITS {
+        let ideal_divider = (peripheral_frequency as u64 * period_ps) / (frame_bits as u64 * PS_PER_SECOND);
+
+        for divider in [ideal_divider, ideal_divider + 1] {
+            if divider < 2 {
+                continue;
+            }
+
+            let Ok(baud) = calculate_baud_divider(peripheral_frequency, (peripheral_frequency as u64 / divider) as u32) else {
+                continue;
+            };
+            let Some(candidate) = evaluate(baud.frequency, frame_bits, timing) else {
+                continue;
+            };
+
+            if best.map_or(true, |best| candidate.max_error < best.max_error) {
+                best = Some(candidate);
+            }
+        }
+    }
+
+    best.ok_or(SPIError::BaudRateUnreachable)
+}
+
+// Rounds the high times to whole SPI bits and works out what that gives
+fn evaluate(baudrate: u32, frame_bits: u8, timing: &LedTiming) -> Option<SpiLedTiming> {
+    let bit_ps = PS_PER_SECOND / baudrate as u64;
+    let high_bits = |target_ns: u32| (((target_ns as u64 * 1000) + (bit_ps / 2)) / bit_ps).max(1) as u8;
+
+    let zero_high = high_bits(timing.t0h);
+    let one_high = high_bits(timing.t1h).max(zero_high + 1);
+    if one_high >= frame_bits {
+        return None;
+    }
+
+    Some(bit_timing(baudrate, frame_bits, zero_high, one_high, timing))
+}
+
+// What comes out when each LED bit is frame_bits SPI bits with zero_high or one_high of them high
+// For encodings with fixed patterns, one_high has to be less than frame_bits
+pub fn bit_timing(baudrate: u32, frame_bits: u8, zero_high: u8, one_high: u8, timing: &LedTiming) -> SpiLedTiming {
+    let bit_ps = PS_PER_SECOND / baudrate as u64;
+    let to_ns = |bits: u8| ((bits as u64 * bit_ps) / 1000) as u32;
+
+    // High bits first since frames go out MSB first
+    let pattern = |high: u8| (((1u32 << high) - 1) << (frame_bits - high)) as u16;
+
+    let t0h = to_ns(zero_high);
+    let t1h = to_ns(one_high);
+    let t0l = to_ns(frame_bits - zero_high);
+    let t1l = to_ns(frame_bits - one_high);
+    let max_error = [
+        t0h.abs_diff(timing.t0h),
+        t1h.abs_diff(timing.t1h),
+        t0l.abs_diff(timing.t0l),
+        t1l.abs_diff(timing.t1l),
+    ].into_iter().max().unwrap_or(0);
+
+    SpiLedTiming {
+        baudrate,
+        frame_bits,
+        logic_0: pattern(zero_high),
+        logic_1: pattern(one_high),
+        t0h,
+        t1h,
+        t0l,
+        t1l,
+        max_error,
+        tolerance: timing.tolerance,
+        reset: Duration::from_micros(timing.reset_us as u64),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (clk_peri, chip, frame_bits, logic_0, logic_1)
+    const EXPECTED: [(u32, LedChip, u8, u16, u16); 9] = [
+        (48_000_000, LedChip::Ws2812b, 6, 0b11_0000, 0b11_1100),
+        (48_000_000, LedChip::Ws2811, 15, 0b111_0000_0000_0000, 0b111_1111_0000_0000),
+        (48_000_000, LedChip::Sk6812, 15, 0b111_1000_0000_0000, 0b111_1111_0000_0000),
+        (125_000_000, LedChip::Ws2812b, 16, 0b1111_1000_0000_0000, 0b1111_1111_1100_0000),
+        (125_000_000, LedChip::Ws2811, 10, 0b11_0000_0000, 0b11_1110_0000),
+        (125_000_000, LedChip::Sk6812, 4, 0b1000, 0b1100),
+        (133_000_000, LedChip::Ws2812b, 6, 0b11_0000, 0b11_1100),
+        (133_000_000, LedChip::Ws2811, 14, 0b11_1000_0000_0000, 0b11_1111_1000_0000),
+        (133_000_000, LedChip::Sk6812, 4, 0b1000, 0b1100),
+    ];
+
+    #[test]
+    fn every_chip_fits_at_common_clocks() {
+        for (peripheral_frequency, chip, frame_bits, logic_0, logic_1) in EXPECTED {
+            let timing = calculate_spi_timing(peripheral_frequency, &chip.timing()).unwrap();
+            let context = format!("{:?} at {}Hz", chip, peripheral_frequency);
+
+            assert!(timing.within_tolerance(), "{} off by {}ns", context, timing.max_error);
+            assert_eq!(timing.frame_bits, frame_bits, "{}", context);
+            assert_eq!(timing.logic_0, logic_0, "{}", context);
+            assert_eq!(timing.logic_1, logic_1, "{}", context);
+            assert_eq!(timing.spi_config().data_bits, frame_bits, "{}", context);
+        }
+    }
+
+    #[test]
+    fn reported_times_match_the_patterns() {
+        for (peripheral_frequency, chip, ..) in EXPECTED {
+            let led = chip.timing();
+            let timing = calculate_spi_timing(peripheral_frequency, &led).unwrap();
+            let bit_ps = PS_PER_SECOND / timing.baudrate as u64;
+            let to_ns = |bits: u32| ((bits as u64 * bit_ps) / 1000) as u32;
+
+            // Both patterns are a run of high bits from the MSB, the rest of the frame is low
+            let frame_bits = timing.frame_bits as u32;
+            let high_0 = timing.logic_0.count_ones();
+            let high_1 = timing.logic_1.count_ones();
+            assert_eq!(timing.logic_0 as u32 >> (frame_bits - high_0), (1 << high_0) - 1);
+            assert_eq!(timing.logic_1 as u32 >> (frame_bits - high_1), (1 << high_1) - 1);
+            assert!(high_1 > high_0);
+
+            assert_eq!(timing.t0h, to_ns(high_0));
+            assert_eq!(timing.t1h, to_ns(high_1));
+            assert_eq!(timing.t0l, to_ns(frame_bits - high_0));
+            assert_eq!(timing.t1l, to_ns(frame_bits - high_1));
+            assert_eq!(timing.reset, Duration::from_micros(led.reset_us as u64));
+        }
+    }
+
+    #[test]
+    fn unreachable_timing_fails() {
+        // 10MHz can't get anywhere near a 50ns high time
+        let fast = LedTiming { t0h: 50, t1h: 100, t0l: 100, t1l: 50, tolerance: 10, reset_us: 50 };
+        assert_eq!(calculate_spi_timing(10_000_000, &fast).unwrap_err(), SPIError::BaudRateUnreachable);
+    }
+}
@@ -0,0 +1,103 @@
+#!/usr/bin/env python3
+"""Pretty-prints an SPI trace dump from pico/src/spi_trace.rs
+
+Usage: decode_spi_trace.py <dump file>  (use - for stdin)
+
+The firmware has to be built with --features spi-trace, it then shows up as a second
+serial port. Writing anything to that port sends the trace back, e.g.
+    stty -F /dev/ttyACM1 raw && printf d > /dev/ttyACM1 && timeout 1 cat /dev/ttyACM1 > trace.bin
+"""
+
+import struct
+import sys
+
+HEADER = struct.Struct("<4sBIH")
+RECORD = struct.Struct("<BBBBHHBQI")
+
+KINDS = ["blocking", "dma", "interrupt"]
+FORMATS = ["motorola", "ti", "microwire", "reserved"]
+ERRORS = [
+    "BaudRateUnreachable",
+    "Timeout",
+    "NoDmaChannel",
+    "LengthMismatch",
+    "NotSlave",
+    "BusBusy",
+    "WrongFormat",
+    "Overrun",
+]
+
+
+def describe_config(cr0, cpsr, clk_peri):
+    data_bits = (cr0 & 0xF) + 1
+    frame_format = FORMATS[(cr0 >> 4) & 0x3]
+    polarity = (cr0 >> 6) & 0x1
+    phase = (cr0 >> 7) & 0x1
+    scr = (cr0 >> 8) & 0xFF
+    baud = clk_peri // (cpsr * (scr + 1)) if cpsr else 0
+    mode = (polarity << 1) | phase
+    return f"{frame_format} mode {mode}, {data_bits} bit, {baud} Hz"
+
+
+def describe_status(status):
+    if status == 0:
+        return "ok"
+    index = status - 1
+    return ERRORS[index] if index < len(ERRORS) else f"error {index}"
+
+
+def format_words(words, data_bits):
+    width = (data_bits + 3) // 4
+    return " ".join(f"{word:0{width}x}" for word in words)
+
+
+def decode(data):
+    magic, version, clk_peri, count = HEADER.unpack_from(data, 0)
+    if magic != b"SPTR":
+        raise ValueError("not an SPI trace dump")
+    if version != 1:
+        raise ValueError(f"unsupported trace version {version}")
+
+    print(f"clk_peri {clk_peri} Hz, {count} transactions")
+    offset = HEADER.size
+    previous_start = None
+
+    for _ in range(count):
+        (instance, kind, status, cpsr, cr0, word_count, stored,
+         start_us, duration_us) = RECORD.unpack_from(data, offset)
+        offset += RECORD.size
+
+        tx = struct.unpack_from(f"<{stored}H", data, offset)
+        offset += stored * 2
+        rx = struct.unpack_from(f"<{stored}H", data, offset)
+        offset += stored * 2
+
+        gap = "" if previous_start is None else f" (+{start_us - previous_start} us)"
+        previous_start = start_us
+        kind_name = KINDS[kind] if kind < len(KINDS) else f"kind {kind}"
+        data_bits = (cr0 & 0xF) + 1
+        truncated = "" if stored == word_count else f" (first {stored} of {word_count})"
+
+        print(f"[{start_us:>12} us{gap}] SPI{instance} {kind_name}, "
+              f"{describe_config(cr0, cpsr, clk_peri)}, {duration_us} us, {describe_status(status)}")
+        print(f"    {word_count} words{truncated}")
+        print(f"    tx: {format_words(tx, data_bits)}")
+        print(f"    rx: {format_words(rx, data_bits)}")
+
+
+def main():
+    if len(sys.argv) != 2:
+        print(__doc__, file=sys.stderr)
+        sys.exit(1)
+
+    if sys.argv[1] == "-":
+        data = sys.stdin.buffer.read()
+    else:
+        with open(sys.argv[1], "rb") as dump:
+            data = dump.read()
+
+    decode(data)
+
+
+if __name__ == "__main__":
+    main()